@@ -1,595 +1,7936 @@
 
+#![allow(clippy::new_ret_no_self)]
+
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::fmt;
+use std::io::Read;
 use std::vec::Vec;
-
-// TODO: Simplify functions
-// TODO: Parse functions
+use std::collections::{HashMap, HashSet};
+use std::collections::BTreeSet;
+#[cfg(feature = "units")]
+use std::collections::BTreeMap;
+
+#[cfg(feature = "rational")]
+use num_rational::Ratio;
+#[cfg(feature = "rational")]
+use num_traits::{CheckedAdd, CheckedSub, CheckedMul, CheckedDiv};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "rand")]
+use rand::Rng;
+#[cfg(feature = "bigdecimal")]
+use bigdecimal::{BigDecimal, Context, FromPrimitive, Zero};
 
 pub type Function = Rc<dyn FunctionTrait>;
 
 pub trait FunctionTrait: fmt::Display {
 
     fn eval(&self, x: &f64) -> f64;
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64;
     fn diff(&self) -> Function;
     fn expand_vec(&self) -> Vec<Function>;
+    fn accept(&self, visitor: &mut dyn Visitor);
+}
+
+// A default method only knows Self: ?Sized, which can't be turned into a trait object,
+// so the defaults below go through this to recurse
+pub trait AsVisitor {
+
+    fn as_visitor(&mut self) -> &mut dyn Visitor;
+}
+
+impl<T: Visitor> AsVisitor for T {
+
+    fn as_visitor(&mut self) -> &mut dyn Visitor { self }
 }
 
-impl FunctionTrait {
+// Every visit defaults to walking the children in order, so an implementation only needs
+// the node kinds it cares about and keeps compiling when new kinds are added
+pub trait Visitor: AsVisitor {
+
+    fn visit_sum(&mut self, left: &Function, right: &Function) { left.accept(self.as_visitor()); right.accept(self.as_visitor()); }
+    fn visit_difference(&mut self, left: &Function, right: &Function) { left.accept(self.as_visitor()); right.accept(self.as_visitor()); }
+    fn visit_negative(&mut self, source: &Function) { source.accept(self.as_visitor()); }
+    fn visit_product(&mut self, left: &Function, right: &Function) { left.accept(self.as_visitor()); right.accept(self.as_visitor()); }
+    fn visit_quotient(&mut self, top: &Function, bottom: &Function) { top.accept(self.as_visitor()); bottom.accept(self.as_visitor()); }
+    fn visit_composed(&mut self, source: &Function, target: &Function) { source.accept(self.as_visitor()); target.accept(self.as_visitor()); }
+    fn visit_unary(&mut self, _unary: &UnaryFunction) {}
+    fn visit_variable(&mut self, _name: &str) {}
+    fn visit_clamp(&mut self, source: &Function, _lo: f64, _hi: f64) { source.accept(self.as_visitor()); }
+    fn visit_piecewise(&mut self, _threshold: f64, below: &Function, above: &Function) { below.accept(self.as_visitor()); above.accept(self.as_visitor()); }
+    fn visit_opaque(&mut self, _opaque: &OpaqueFunction) {}
+    fn visit_power(&mut self, base: &Function, _power: i32) { base.accept(self.as_visitor()); }
+
+    // Coefficients in increasing order of power, the default walks the equivalent sum of
+    // monomials built on the spot
+    fn visit_polynomial(&mut self, coeffs: &[f64]) { polynomial_tree(coeffs).accept(self.as_visitor()); }
+
+    // Memoised functions are their own node so that rebuilding a tree keeps the cache, the
+    // default sees straight through to the wrapped function
+    fn visit_memo(&mut self, memo: &MemoFunction) { memo.inner.accept(self.as_visitor()); }
+
+    // Named functions are only a label on the inner function, which the default walks
+    fn visit_named(&mut self, _name: &str, inner: &Function) { inner.accept(self.as_visitor()); }
+
+    // Unit annotations wrap the annotated function, the default walks it as if the
+    // annotation wasn't there
+    #[cfg(feature = "units")]
+    fn visit_unit(&mut self, _unit: &Unit, inner: &Function) { inner.accept(self.as_visitor()); }
+}
+
+impl dyn FunctionTrait {
 
+    // Expansion distributes products and numerators over sums, so the result is a sum of terms
+    // that agrees with the original wherever every term is finite, up to rounding. Denominators
+    // and compositions are kept whole, and nothing is simplified or collected
     pub fn expand(&self) -> Function {
 
         SumFunction::from_many(&self.expand_vec())
     }
-}
 
-pub trait FunctionOf {
+    pub fn find_root(&self, guess: f64, max_iter: usize, tol: f64) -> Option<f64> {
 
-    fn of(self, other: Self) -> Self;
-}
+        let derivative = self.diff();
 
-pub trait FunctionAdd {
+        newton(self, &*derivative, guess, max_iter, tol)
+    }
 
-    fn add(self, other: Self) -> Self;
-}
+    pub fn all_roots(&self, a: f64, b: f64, samples: usize, tol: f64) -> Vec<f64> {
 
-pub trait FunctionSub {
+        let derivative = self.diff();
+        let mut roots: Vec<f64> = Vec::new();
 
-    fn sub(self, other: Self) -> Self;
-}
+        for guess in grid(a, b, samples) {
 
-pub trait FunctionNeg {
+            if let Some(root) = newton(self, &*derivative, guess, NEWTON_MAX_ITER, tol) {
 
-    fn neg(self) -> Self;
-}
+                let in_range = root >= a - tol && root <= b + tol;
+                let is_new = roots.iter().all(|known| (known - root).abs() > tol);
 
-pub trait FunctionMul {
+                if in_range && is_new {
 
-    fn mul(self, other: Self) -> Self;
-}
+                    roots.push(root);
+                }
+            }
+        }
 
-pub trait FunctionDiv {
+        roots.sort_by(|l, r| l.partial_cmp(r).unwrap());
+        roots
+    }
 
-    fn div(self, other: Self) -> Self;
-}
+    pub fn substitute_var(&self, name: &str, replacement: &Function) -> Function {
 
-impl FunctionOf for Function {
+        match Shape::of(self) {
 
-    fn of(self, other: Function) -> Function {
+            Shape::Unary(UnaryFunction::Const(c)) => UnaryFunction::Const(c).new(),
+            Shape::Unary(UnaryFunction::Id) if name == "x" => Rc::clone(replacement),
+            Shape::Unary(unary) if name == "x" => unary.new().of(Rc::clone(replacement)),
+            Shape::Variable(ref var) if var == name => Rc::clone(replacement),
 
-        ComposedFunction::new(self, other)
-    }
-}
+            // The threshold test reads x directly, so the whole piecewise has to be composed
+            Shape::Piecewise(threshold, below, above) if name == "x" => PiecewiseFunction::new(threshold, below, above).of(Rc::clone(replacement)),
+            Shape::Opaque(opaque) if name == "x" => ComposedFunction::new(Rc::new(opaque), Rc::clone(replacement)),
+            Shape::Polynomial(coeffs) if name == "x" => PolynomialFunction::new(coeffs).of(Rc::clone(replacement)),
 
-impl FunctionAdd for Function {
+            // The source of a composition sees the target as its x, so only other names reach it
+            Shape::Composed(source, target) => {
 
-    fn add(self, other: Function) -> Function {
+                let target = target.substitute_var(name, replacement);
+                let source = if name == "x" { source } else { source.substitute_var(name, replacement) };
 
-        SumFunction::new(self, other)
-    }
-}
+                source.of(target)
+            },
 
-impl FunctionSub for Function {
+            shape => shape.map(|child| child.substitute_var(name, replacement)),
+        }
+    }
 
-    fn sub(self, other: Function) -> Function {
+    // Replaces every named variable in values by its constant, names that aren't present
+    // are left free
+    pub fn bind_constants(&self, values: &HashMap<String, f64>) -> Function {
 
-        DifferenceFunction::new(self, other)
+        values.iter().fold(self.to_function(), |bound, (name, value)| bound.substitute_var(name, &UnaryFunction::Const(*value).new()))
     }
-}
 
-impl FunctionNeg for Function {
+    pub fn partial_diff(&self, var: &str) -> Function {
 
-    fn neg(self) -> Function {
+        match Shape::of(self) {
 
-        NegativeFunction::new(self)
-    }
-}
+            Shape::Sum(left, right) => left.partial_diff(var).add(right.partial_diff(var)),
+            Shape::Difference(left, right) => left.partial_diff(var).sub(right.partial_diff(var)),
+            Shape::Negative(source) => source.partial_diff(var).neg(),
 
-impl FunctionMul for Function {
+            Shape::Product(left, right) => {
 
-    fn mul(self, other: Function) -> Function {
+                let l_term = left.partial_diff(var).mul(Rc::clone(&right));
+                let r_term = right.partial_diff(var).mul(left);
 
-        ProductFunction::new(self, other)
-    }
-}
+                l_term.add(r_term)
+            },
 
-impl FunctionDiv for Function {
+            Shape::Quotient(top, bottom) => {
 
-    fn div(self, other: Function) -> Function {
+                let b_sqr = Rc::clone(&bottom).mul(Rc::clone(&bottom));
 
-        QuotientFunction::new(self, other)
-    }
-}
+                let l_term = top.partial_diff(var).mul(Rc::clone(&bottom));
+                let r_term = bottom.partial_diff(var).mul(top);
 
-pub struct SumFunction {
+                (l_term.sub(r_term)).div(b_sqr)
+            },
 
-    left: Function,
-    right: Function,
-}
+            // The source's own x is bound to the target, any other variable in it is free
+            Shape::Composed(source, target) => {
 
-impl SumFunction {
+                let chain = source.diff().of(Rc::clone(&target)).mul(target.partial_diff(var));
 
-    pub fn new(left: Function, right: Function) -> Function {
+                if var == "x" { chain } else { chain.add(source.partial_diff(var).of(target)) }
+            },
 
-        Rc::new(SumFunction { left, right })
-    }
+            Shape::Unary(unary) if var == "x" => unary.diff(),
+            Shape::Unary(_) => UnaryFunction::Const(0.0).new(),
+            Shape::Variable(ref name) if name == var => UnaryFunction::Const(1.0).new(),
+            Shape::Variable(_) => UnaryFunction::Const(0.0).new(),
 
-    pub fn from_many(many: &[Function]) -> Function {
+            Shape::Clamp(source, lo, hi) => {
 
-        match many {
+                let gate = ClampFunction::gate(lo, hi).of(Rc::clone(&source));
 
-            [] => panic!("Cannot sum an empty list!"),
-            [single] => Rc::clone(single),
-            [left, right] => SumFunction::new(Rc::clone(left), Rc::clone(right)),
-            _ => SumFunction::new(Rc::clone(&many[0]), SumFunction::from_many(&many[1..])),
+                source.partial_diff(var).mul(gate)
+            },
+
+            Shape::Piecewise(threshold, below, above) => PiecewiseFunction::new(threshold, below.partial_diff(var), above.partial_diff(var)),
+            Shape::Opaque(opaque) if var == "x" => opaque.diff(),
+            Shape::Opaque(_) => UnaryFunction::Const(0.0).new(),
+
+            Shape::Power(_, 0) => UnaryFunction::Const(0.0).new(),
+
+            Shape::Power(base, power) => {
+
+                let lowered = IntPowFunction::new(Rc::clone(&base), power - 1);
+
+                UnaryFunction::Const(power as f64).new().mul(lowered).mul(base.partial_diff(var))
+            },
+
+            Shape::Polynomial(coeffs) if var == "x" => PolynomialFunction::new(coeffs).diff(),
+            Shape::Polynomial(_) => UnaryFunction::Const(0.0).new(),
+            Shape::Memo(memo) => MemoFunction::new(memo.inner.partial_diff(var)),
+            Shape::Named(_, inner) => inner.partial_diff(var),
+            #[cfg(feature = "units")]
+            Shape::Unit(inner, _) => inner.partial_diff(var),
         }
     }
-}
 
-impl FunctionTrait for SumFunction {
+    // The chain rule df/dt as the sum of each partial derivative times its variable's rate,
+    // variables missing from rates are taken to be constant in t
+    pub fn total_derivative(&self, rates: &HashMap<String, Function>) -> Function {
 
-    fn eval(&self, x: &f64) -> f64 {
+        let mut vars: Vec<&String> = rates.keys().collect();
+        vars.sort();
 
-        self.left.eval(x) + self.right.eval(x)
-    }
+        let terms: Vec<Function> = vars.into_iter().map(|var| self.partial_diff(var).mul(Rc::clone(&rates[var])).simplify_fully()).collect();
 
-    fn diff(&self) -> Function {
-        
-        self.left.diff().add(self.right.diff())
+        if terms.is_empty() { UnaryFunction::Const(0.0).new() } else { SumFunction::from_many(&terms) }
     }
 
-    fn expand_vec(&self) -> Vec<Function> {
+    // The direction is normalized first, so only its orientation matters
+    pub fn directional_derivative(&self, vars: &[&str], direction: &[f64]) -> Function {
 
-        let mut result = Vec::new();
+        assert_eq!(vars.len(), direction.len(), "Need one direction component per variable!");
 
-        for exp in self.left.expand_vec() {
+        let norm = direction.iter().map(|d| d * d).sum::<f64>().sqrt();
+        assert!(norm > 0.0, "Cannot differentiate along a zero direction!");
 
-            result.push(exp);
-        }
+        let terms: Vec<Function> = vars.iter().zip(direction).filter(|(_, d)| **d != 0.0).map(|(var, d)| {
 
-        for exp in self.right.expand_vec() {
+            UnaryFunction::Const(d / norm).new().mul(self.partial_diff(var)).simplify_fully()
 
-            result.push(exp);
-        }
+        }).collect();
 
-        result 
+        if terms.is_empty() { UnaryFunction::Const(0.0).new() } else { SumFunction::from_many(&terms).simplify_fully() }
     }
-}
 
-impl fmt::Display for SumFunction {
+    // Only exp/ln, affine maps, and compositions or constant shifts and scalings of those
+    // are inverted, anything else gives None even if it happens to be injective
+    pub fn analytic_inverse(&self) -> Option<Function> {
 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        inverse_of(self).map(|inverse| inverse.simplify_fully())
+    }
+
+    pub fn as_affine(&self) -> Option<(f64, f64)> {
 
-        write!(f, "({}) + ({})", self.left.to_string(), self.right.to_string())
+        affine_of(self)
     }
-}
 
-pub struct DifferenceFunction {
+    // Compiles the tree into closures once, evaluating polynomials in a repeated inner
+    // function (x itself, sin(x) in sin(x)*sin(x) + sin(x), or a composition's target) by
+    // Horner's method on a single evaluation of that inner function
+    pub fn fast_eval(&self) -> Box<dyn Fn(f64) -> f64> {
 
-    left: Function,
-    right: Function,
-}
+        compile_fast(self)
+    }
 
-impl DifferenceFunction {
+    pub fn compile(&self) -> CompiledFunction {
 
-    fn new(left: Function, right: Function) -> Function {
+        CompiledFunction::of(self)
+    }
 
-        Rc::new(DifferenceFunction { left, right })
+    // Other variables count as constants, as do unary functions of x when var isn't x
+    pub fn is_polynomial_in(&self, var: &str) -> bool {
+
+        polynomial_in(self, var)
     }
-}
 
-impl FunctionTrait for DifferenceFunction {
+    // Variables get their units from UnitFunction annotations, transcendental functions
+    // need dimensionless arguments and sums need matching units
+    #[cfg(feature = "units")]
+    pub fn check_dimensions(&self) -> Result<(), UnitError> {
 
-    fn eval(&self, x: &f64) -> f64 {
+        infer_unit(self, &None).map(|_| ())
+    }
 
-        self.left.eval(x) - self.right.eval(x)
+    // Coefficients in increasing order of power, with no trailing zeros
+    pub fn as_polynomial(&self) -> Option<Vec<f64>> {
+
+        polynomial_of(self).map(trim_polynomial)
     }
 
-    fn diff(&self) -> Function {
-        
-        self.left.diff().sub(self.right.diff())
+    pub fn coefficient_of(&self, power: usize) -> Option<f64> {
+
+        self.as_polynomial().map(|coeffs| coeffs.get(power).cloned().unwrap_or(0.0))
     }
 
-    fn expand_vec(&self) -> Vec<Function> {
+    // None for non-polynomials and for zero, which has no leading coefficient
+    pub fn monic(&self) -> Option<Function> {
 
-        let mut result = Vec::new();
+        let coeffs = self.as_polynomial()?;
+        let lead = *coeffs.last()?;
 
-        for exp in self.left.expand_vec() {
+        Some(PolynomialFunction::new(coeffs.iter().map(|c| c / lead).collect()))
+    }
 
-            result.push(exp);
-        }
+    pub fn leading_term_as_x_to_infinity(&self) -> Function {
 
-        for exp in self.right.expand_vec() {
+        if let Some(coeffs) = self.as_polynomial() {
 
-            result.push(exp.neg());
+            return leading_monomial(&coeffs);
         }
 
-        result 
-    }
-}
-
-impl fmt::Display for DifferenceFunction {
+        if let Shape::Quotient(top, bottom) = Shape::of(self) {
 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if let (Some(top), Some(bottom)) = (top.as_polynomial(), bottom.as_polynomial()) {
 
-        write!(f, "({}) - ({})", self.left.to_string(), self.right.to_string())
-    }
-}
+                if let (Some(t_lead), Some(b_lead)) = (top.last(), bottom.last()) {
 
-pub struct NegativeFunction {
+                    let coeff = t_lead / b_lead;
+                    let (t_deg, b_deg) = (top.len() - 1, bottom.len() - 1);
 
-    source: Function,
-}
+                    if t_deg >= b_deg {
 
-impl NegativeFunction {
+                        return monomial(coeff, t_deg - b_deg);
+                    }
 
-    fn new(source: Function) -> Function {
+                    return UnaryFunction::Const(coeff).new().div(monomial(1.0, b_deg - t_deg));
+                }
+            }
+        }
 
-        Rc::new(NegativeFunction { source })
+        self.to_function()
     }
-}
 
-impl FunctionTrait for NegativeFunction {
+    // Products of periodic terms can have a shorter period than either factor (sin^2 has period pi),
+    // which this structural analysis doesn't detect
+    pub fn period(&self) -> Option<f64> {
 
-    fn eval(&self, x: &f64) -> f64 {
+        match period_of(self) {
 
-        -self.source.eval(x)
+            Periodicity::Periodic(period) => Some(period),
+            _ => None,
+        }
     }
 
-    fn diff(&self) -> Function {
+    pub fn diff_simplified(&self) -> Function {
 
-        self.source.diff().neg()
+        self.diff().simplify_fully()
     }
 
-    fn expand_vec(&self) -> Vec<Function> {
+    // The quotient rule squares the denominator as g * g with both sides the same Rc, this
+    // rebuilds every such product as g^2 so the subtree is only walked once
+    pub fn diff_symmetric_quotient(&self) -> Function {
 
-        self.source.expand_vec().iter().map(|term| { Rc::clone(&term).neg() }).collect()
+        share_squares(&*self.diff())
     }
-}
 
-impl fmt::Display for NegativeFunction {
+    // Unlike diff this refuses to fall back to a numeric derivative for opaque functions
+    // that weren't given one
+    pub fn try_diff(&self) -> Result<Function, DiffError> {
 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match find_missing_diff(self) {
 
-        write!(f, "-({})", self.source.to_string())
+            Some(name) => Err(DiffError::NoDerivative(name)),
+            None => Ok(self.diff()),
+        }
     }
-}
 
-pub struct ProductFunction {
-
-    left: Function,
-    right: Function,
-}
+    pub fn wrap_with_name(&self, name: &str) -> Function {
 
-impl ProductFunction {
+        NamedFunction::new(name, self.to_function())
+    }
 
-    pub fn new(left: Function, right: Function) -> Function {
+    // Mirror image across the y axis, f(-x)
+    pub fn reflect_x(&self) -> Function {
 
-        Rc::new(ProductFunction { left, right })
+        self.to_function().of(UnaryFunction::Id.new().neg())
     }
-}
-
-impl FunctionTrait for ProductFunction {
 
-    fn eval(&self, x: &f64) -> f64 {
+    // Mirror image across the x axis, -f(x)
+    pub fn reflect_y(&self) -> Function {
 
-        self.left.eval(x) * self.right.eval(x)
+        self.to_function().neg()
     }
 
-    fn diff(&self) -> Function {
+    // The left hand side of the linear ODE a_n y^(n) + ... + a_0 y = 0 with self as y,
+    // coefficients being listed from a_n down to a_0
+    pub fn ode_residual(&self, coeffs: &[Function]) -> Function {
 
-        let l_diff = self.left.diff();
-        let r_diff = self.right.diff();
+        let mut derivative = self.to_function();
+        let mut terms = Vec::new();
 
-        let l_clone = Rc::clone(&self.left);
-        let r_clone = Rc::clone(&self.right);
+        for coeff in coeffs.iter().rev() {
 
-        let l_term = l_diff.mul(r_clone);
-        let r_term = r_diff.mul(l_clone);
+            terms.push(Rc::clone(coeff).mul(Rc::clone(&derivative)));
+            derivative = derivative.diff();
+        }
 
-        l_term.add(r_term)
+        if terms.is_empty() { UnaryFunction::Const(0.0).new() } else { SumFunction::from_many(&terms) }
     }
 
-    fn expand_vec(&self) -> Vec<Function> {
-
-        let l_exps = self.left.expand_vec();
-        let r_exps = self.right.expand_vec();
+    // Differentiates via f' = f * (ln |f|)', splitting the log over products, quotients and
+    // powers first so each factor u^n contributes a single n u' / u term. Multiplying that
+    // by f as it stands would be 0 * inf wherever u vanishes, so the u is cancelled out and
+    // the term is built as n u^(n - 1) u' times the other factors instead
+    pub fn diff_logarithmic(&self) -> Function {
 
-        let mut result = Vec::new();
+        let mut factors = Vec::new();
+        let mut scale = 1.0;
+        log_factors(self.to_function(), 1, &mut factors, &mut scale);
+        factors.retain(|(_, exponent)| *exponent != 0);
 
-        for l_exp in &l_exps {
+        let power = |factor: &Function, exponent: i32| match exponent {
 
-            for r_exp in &r_exps {
-                
-                result.push(Rc::clone(l_exp).mul(Rc::clone(r_exp)));
-            }
-        }
+            0 => None,
+            1 => Some(Rc::clone(factor)),
+            _ => Some(IntPowFunction::new(Rc::clone(factor), exponent)),
+        };
 
-        result
-    }
-}
+        let terms = factors.iter().enumerate().map(|(i, (factor, exponent))| {
 
-impl fmt::Display for ProductFunction {
+            let others = factors.iter().enumerate().filter(|(j, _)| *j != i).filter_map(|(_, (other, n))| power(other, *n));
+            let coefficient = UnaryFunction::Const(scale * *exponent as f64).new().mul(factor.diff());
 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            power(factor, exponent - 1).into_iter().chain(others).fold(coefficient, |term, other| term.mul(other))
+        });
 
-        write!(f, "({}) * ({})", self.left.to_string(), self.right.to_string())
+        terms.reduce(|sum, term| sum.add(term)).unwrap_or_else(|| UnaryFunction::Const(0.0).new())
     }
-}
-
-pub struct QuotientFunction {
 
-    top: Function,
-    bottom: Function,
-}
+    pub fn nth_diff(&self, n: usize) -> Function {
 
-impl QuotientFunction {
+        (0..n).fold(self.to_function(), |f, _| f.diff())
+    }
 
-    fn new(top: Function, bottom: Function) -> Function {
+    pub fn nth_diff_simplified(&self, n: usize) -> Function {
 
-        Rc::new(QuotientFunction { top, bottom })
+        (0..n).fold(self.simplify_fully(), |f, _| f.diff().simplify_fully())
     }
-}
-
-impl FunctionTrait for QuotientFunction {
 
-    fn eval(&self, x: &f64) -> f64 {
+    // Coefficients of (x - center)^k up to k = order
+    pub fn taylor_coefficients(&self, center: f64, order: usize) -> Vec<f64> {
 
-        self.top.eval(x) / self.bottom.eval(x)
-    }
+        let mut derivative = self.simplify_fully();
+        let mut factorial = 1.0;
+        let mut coeffs = Vec::new();
 
-    fn diff(&self) -> Function {
+        for k in 0..=order {
 
-        let t_diff = self.top.diff();
-        let b_diff = self.bottom.diff();
+            if k > 0 {
 
-        let b_sqr = Rc::clone(&self.bottom).mul(Rc::clone(&self.bottom));
+                derivative = derivative.diff().simplify_fully();
+                factorial *= k as f64;
+            }
 
-        let l_term = t_diff.mul(Rc::clone(&self.bottom));
-        let r_term = b_diff.mul(Rc::clone(&self.top));
+            coeffs.push(derivative.eval(&center) / factorial);
+        }
 
-        (l_term.sub(r_term)).div(b_sqr)
+        coeffs
     }
 
-    fn expand_vec(&self) -> Vec<Function> {
+    // The [m/n] Padé approximant around center, as a quotient of polynomials in x - center;
+    // None when the Taylor coefficients give a singular system for the denominator, or
+    // aren't finite
+    pub fn pade(&self, center: f64, m: usize, n: usize) -> Option<Function> {
 
-        let t_exps = self.top.expand_vec();
+        let c = self.taylor_coefficients(center, m + n);
+        let coeff = |k: isize| if k < 0 { 0.0 } else { c[k as usize] };
 
-        let mut result = Vec::new();
+        let matrix = (m + 1..=m + n).map(|k| (1..=n).map(|j| coeff(k as isize - j as isize)).collect()).collect();
+        let rhs = (m + 1..=m + n).map(|k| -c[k]).collect();
 
-        for t_exp in &t_exps {
+        let mut q = vec![1.0];
+        q.extend(solve_linear(matrix, rhs)?);
 
-            result.push(Rc::clone(t_exp).div(Rc::clone(&self.bottom)));
-        }
+        let p = (0..=m).map(|i| (0..=i.min(n)).map(|j| q[j] * c[i - j]).sum()).collect();
 
-        result
-    }
-}
+        let (top, bottom) = (PolynomialFunction::new(p), PolynomialFunction::new(q));
 
-impl fmt::Display for QuotientFunction {
+        if center == 0.0 {
 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            return Some(top.div(bottom));
+        }
+
+        let shift = UnaryFunction::Id.new().sub(UnaryFunction::Const(center).new());
 
-        write!(f, "({}) / ({})", self.top.to_string(), self.bottom.to_string())
+        Some(top.of(Rc::clone(&shift)).div(bottom.of(shift)))
     }
-}
 
-pub struct ComposedFunction {
+    pub fn taylor_error_bound(&self, center: f64, order: usize, x: f64) -> f64 {
 
-    target: Function,
-    source: Function,
-}
+        let derivative = self.nth_diff_simplified(order + 1);
 
-impl ComposedFunction {
+        let max_magnitude = (0..=TAYLOR_BOUND_SAMPLES)
+            .map(|i| center + (x - center) * i as f64 / TAYLOR_BOUND_SAMPLES as f64)
+            .map(|t| derivative.eval(&t).abs())
+            .fold(0.0, f64::max);
 
-    pub fn new(source: Function, target: Function) -> Function {
+        let factorial = (1..=order + 1).map(|k| k as f64).product::<f64>();
 
-        Rc::new(ComposedFunction { target, source })
+        max_magnitude * (x - center).abs().powi(order as i32 + 1) / factorial
     }
-}
-
-impl FunctionTrait for ComposedFunction {
 
-    fn eval(&self, x: &f64) -> f64 {
+    pub fn simplify(&self) -> Function {
 
-        self.source.eval(&self.target.eval(x))
+        simplify_node(Shape::of(self).map(|child| child.simplify()))
     }
 
-    fn diff(&self) -> Function {
+    pub fn simplify_fully(&self) -> Function {
 
-        let s_diff = self.source.diff();
-        let t_diff = self.target.diff();
+        let mut current = self.simplify();
+
+        loop {
+
+            let next = current.simplify();
+
+            if next.count_nodes() >= current.count_nodes() {
+
+                return current;
+            }
+
+            current = next;
+        }
+    }
+
+    fn to_function(&self) -> Function {
+
+        Shape::of(self).map(Rc::clone)
+    }
+
+    // Rewrites sin(x) and cos(x) in terms of a new variable t = tan(x / 2), anything else
+    // depending on x is kept as it is
+    pub fn tan_half_angle_form(&self) -> Function {
+
+        let t = VariableFunction::new("t");
+        let one = UnaryFunction::Const(1.0).new();
+        let t_sqr = Rc::clone(&t).mul(Rc::clone(&t));
+        let one_plus_t_sqr = Rc::clone(&one).add(Rc::clone(&t_sqr));
+
+        match Shape::of(self) {
+
+            Shape::Unary(UnaryFunction::Sin) => UnaryFunction::Const(2.0).new().mul(t).div(one_plus_t_sqr),
+            Shape::Unary(UnaryFunction::Cos) => one.sub(t_sqr).div(one_plus_t_sqr),
+
+            Shape::Composed(source, target) => match Shape::of(&*target) {
+
+                Shape::Unary(UnaryFunction::Id) => source.tan_half_angle_form(),
+                Shape::Variable(ref name) if name == "x" => source.tan_half_angle_form(),
+                _ => source.of(target.tan_half_angle_form()),
+            },
+
+            shape => shape.map(|child| child.tan_half_angle_form()),
+        }
+    }
+
+    pub fn normalize_signs(&self) -> Function {
+
+        match Shape::of(self) {
+
+            Shape::Sum(left, right) => {
+
+                let left = left.normalize_signs();
+                let right = right.normalize_signs();
+
+                match Shape::of(&*right) {
+
+                    Shape::Negative(source) => left.sub(source),
+                    _ => left.add(right),
+                }
+            },
+
+            Shape::Negative(source) => {
+
+                let source = source.normalize_signs();
+
+                match Shape::of(&*source) {
+
+                    Shape::Negative(inner) => inner,
+                    _ => source.neg(),
+                }
+            },
+
+            shape => shape.map(|child| child.normalize_signs()),
+        }
+    }
+
+    pub fn round_constants(&self, decimals: u32) -> Function {
+
+        match Shape::of(self) {
+
+            Shape::Unary(UnaryFunction::Const(c)) => {
+
+                let scale = 10f64.powi(decimals as i32);
+
+                UnaryFunction::Const((c * scale).round() / scale).new()
+            },
+
+            shape => shape.map(|child| child.round_constants(decimals)),
+        }
+    }
+
+    pub fn drop_small_constants(&self, epsilon: f64) -> Function {
+
+        zero_small_constants(self, epsilon).simplify_fully()
+    }
+
+    pub fn flatten_sum(&self) -> Vec<Function> {
+
+        match Shape::of(self) {
+
+            Shape::Sum(left, right) => {
+
+                let mut terms = left.flatten_sum();
+                terms.extend(right.flatten_sum());
+
+                terms
+            },
+
+            _ => vec![self.to_function()],
+        }
+    }
+
+    pub fn flatten_product(&self) -> Vec<Function> {
+
+        match Shape::of(self) {
+
+            Shape::Product(left, right) => {
+
+                let mut factors = left.flatten_product();
+                factors.extend(right.flatten_product());
+
+                factors
+            },
+
+            _ => vec![self.to_function()],
+        }
+    }
+
+    // Splits each addend into its constant factors multiplied together and the product of
+    // the rest, differences and negations folding into the coefficient's sign. A constant
+    // addend comes out with a base of 1
+    pub fn linear_combination(&self) -> Vec<(f64, Function)> {
+
+        let mut terms = Vec::new();
+        push_signed_terms(self.to_function(), 1.0, &mut terms);
+
+        terms.into_iter().map(|(term, sign)| {
+
+            let (constants, factors): (Vec<Function>, Vec<Function>) = term.flatten_product().into_iter().partition(|factor| const_value(&**factor).is_some());
+            let coefficient = constants.iter().filter_map(|constant| const_value(&**constant)).product::<f64>();
+            let base = if factors.is_empty() { UnaryFunction::Const(1.0).new() } else { ProductFunction::from_many(&factors) };
+
+            (sign * coefficient, base)
+
+        }).collect()
+    }
+
+    // Collects structurally equal factors of each product into integer powers, so x*x*x
+    // becomes x^3 and x^2 * x does too
+    pub fn simplify_powers(&self) -> Function {
+
+        let simplified = Shape::of(self).map(|child| child.simplify_powers());
+
+        if !matches!(Shape::of(&*simplified), Shape::Product(_, _)) {
+
+            return simplified;
+        }
+
+        let mut powers: Vec<(Function, i32)> = Vec::new();
+
+        for factor in simplified.flatten_product() {
+
+            let (base, power) = match Shape::of(&*factor) {
+
+                Shape::Power(base, power) => (base, power),
+                _ => (factor, 1),
+            };
+
+            match powers.iter_mut().find(|(known, _)| known.structurally_equals(&*base)) {
+
+                Some((_, total)) => *total += power,
+                None => powers.push((base, power)),
+            }
+        }
+
+        let factors: Vec<Function> = powers.into_iter().map(|(base, power)| match power {
+
+            1 => base,
+            _ => IntPowFunction::new(base, power),
+
+        }).collect();
+
+        ProductFunction::from_many(&factors)
+    }
+
+    pub fn factor_common(&self) -> Function {
+
+        let terms = self.flatten_sum();
+
+        if terms.len() < 2 {
+
+            return self.to_function();
+        }
+
+        let mut remainders: Vec<Vec<Function>> = terms.iter().map(|term| term.flatten_product()).collect();
+        let mut common = Vec::new();
+
+        // Pull out factors of the first term one at a time while every other term has a copy too
+        for candidate in terms[0].flatten_product() {
+
+            let positions: Vec<Option<usize>> = remainders.iter()
+                .map(|factors| factors.iter().position(|factor| factor.structurally_equals(&*candidate)))
+                .collect();
+
+            if positions.iter().all(Option::is_some) {
+
+                for (factors, position) in remainders.iter_mut().zip(positions) {
+
+                    factors.remove(position.unwrap());
+                }
+
+                common.push(candidate);
+            }
+        }
+
+        if common.is_empty() {
+
+            return self.to_function();
+        }
+
+        let remainder_terms: Vec<Function> = remainders.iter()
+            .map(|factors| if factors.is_empty() { UnaryFunction::Const(1.0).new() } else { ProductFunction::from_many(factors) })
+            .collect();
+
+        ProductFunction::from_many(&common).mul(SumFunction::from_many(&remainder_terms))
+    }
+
+    // Rewrites affine composition targets into the standard form a*x + b, so sin((x + x) + 1)
+    // becomes sin(2x + 1)
+    pub fn canonicalize_affine_arguments(&self) -> Function {
+
+        let canonical = Shape::of(self).map(|child| child.canonicalize_affine_arguments());
+
+        match Shape::of(&*canonical) {
+
+            Shape::Composed(source, target) => match affine_of(&*target) {
+
+                Some((m, c)) => source.of(affine_form(m, c)),
+                None => canonical,
+            },
+
+            _ => canonical,
+        }
+    }
+
+    // Rewrites sin(u)^2 + cos(u)^2 to 1 wherever the squares are spelled as products of
+    // structurally equal factors
+    pub fn simplify_trig(&self) -> Function {
+
+        let simplified = Shape::of(self).map(|child| child.simplify_trig());
+
+        if let Shape::Sum(left, right) = Shape::of(&*simplified) {
+
+            if let (Some((l_kind, l_arg)), Some((r_kind, r_arg))) = (trig_square(&*left), trig_square(&*right)) {
+
+                if l_kind != r_kind && l_arg.structurally_equals(&*r_arg) {
+
+                    return UnaryFunction::Const(1.0).new();
+                }
+            }
+        }
+
+        simplified
+    }
+
+    // Pushes negations into sums and differences and pulls them out of products, so -(a - b)
+    // becomes b - a, -(-a) becomes a and (-a) * b becomes -(a * b)
+    pub fn simplify_negations(&self) -> Function {
+
+        let simplified = Shape::of(self).map(|child| child.simplify_negations());
+
+        match Shape::of(&*simplified) {
+
+            Shape::Negative(source) => negated(source),
+
+            Shape::Product(left, right) => match (Shape::of(&*left), Shape::of(&*right)) {
+
+                (Shape::Negative(a), Shape::Negative(b)) => a.mul(b),
+                (Shape::Negative(a), _) => negated(a.mul(right)),
+                (_, Shape::Negative(b)) => negated(left.mul(b)),
+                _ => simplified,
+            },
+
+            _ => simplified,
+        }
+    }
+
+    // Cleans up differences left by expansion: 0 - b becomes -b, a - (-b) becomes a + b
+    // and a - (b - c) becomes (a - b) + c
+    pub fn simplify_double_negation_in_difference(&self) -> Function {
+
+        let simplified = Shape::of(self).map(|child| child.simplify_double_negation_in_difference());
+
+        match Shape::of(&*simplified) {
+
+            Shape::Difference(left, right) if left.is_zero() => right.neg(),
+
+            Shape::Difference(left, right) => match Shape::of(&*right) {
+
+                Shape::Negative(b) => left.add(b),
+                Shape::Difference(b, c) => left.sub(b).simplify_double_negation_in_difference().add(c),
+                _ => simplified,
+            },
+
+            _ => simplified,
+        }
+    }
+
+    pub fn distribute(&self) -> Function {
+
+        let (left, right) = match Shape::of(self) {
+
+            Shape::Product(left, right) => (left, right),
+            _ => return self.to_function(),
+        };
+
+        match (Shape::of(&*left), Shape::of(&*right)) {
+
+            (_, Shape::Sum(a, b)) => Rc::clone(&left).mul(a).add(left.mul(b)),
+            (_, Shape::Difference(a, b)) => Rc::clone(&left).mul(a).sub(left.mul(b)),
+            (Shape::Sum(a, b), _) => a.mul(Rc::clone(&right)).add(b.mul(right)),
+            (Shape::Difference(a, b), _) => a.mul(Rc::clone(&right)).sub(b.mul(right)),
+            _ => self.to_function(),
+        }
+    }
+
+    // Swaps out the exact shared node target (by pointer, not structure), only the nodes on
+    // the way down to an occurrence get rebuilt
+    pub fn replace_ptr(&self, target: &Function, replacement: &Function) -> Function {
+
+        if std::ptr::eq(self as *const dyn FunctionTrait as *const (), Rc::as_ptr(target) as *const ()) {
+
+            return Rc::clone(replacement);
+        }
+
+        replace_ptr_below(self, target, replacement).unwrap_or_else(|| self.to_function())
+    }
+
+    pub fn combine_quotients(&self) -> Function {
+
+        let combined = Shape::of(self).map(|child| child.combine_quotients());
+
+        let (left, right, sign) = match Shape::of(&*combined) {
+
+            Shape::Sum(left, right) => (left, right, 1.0),
+            Shape::Difference(left, right) => (left, right, -1.0),
+            _ => return combined,
+        };
+
+        match (Shape::of(&*left), Shape::of(&*right)) {
+
+            (Shape::Quotient(l_top, l_bottom), Shape::Quotient(r_top, r_bottom)) => {
+
+                let join = |l: Function, r: Function| if sign > 0.0 { l.add(r) } else { l.sub(r) };
+
+                if l_bottom.structurally_equals(&*r_bottom) {
+
+                    join(l_top, r_top).div(l_bottom)
+                } else {
+
+                    let top = join(l_top.mul(Rc::clone(&r_bottom)), r_top.mul(Rc::clone(&l_bottom)));
+
+                    top.div(l_bottom.mul(r_bottom))
+                }
+            },
+
+            _ => combined,
+        }
+    }
+
+    // Unlike combine_quotients this merges every sum, difference, product, quotient and
+    // integer power along the way, so the result is one quotient whose numerator and
+    // denominator contain no quotients. Compositions and other nodes count as numerators,
+    // and an expression without any division comes back unchanged
+    pub fn as_single_fraction(&self) -> Function {
+
+        match fraction_of(self) {
+
+            (top, Some(bottom)) => top.div(bottom),
+            (top, None) => top,
+        }
+    }
+
+    pub fn numerator(&self) -> Function {
+
+        fraction_of(self).0
+    }
+
+    // 1 for anything without a division in it
+    pub fn denominator(&self) -> Function {
+
+        fraction_of(self).1.unwrap_or_else(|| UnaryFunction::Const(1.0).new())
+    }
+
+    // Roots of the denominator where the numerator doesn't vanish too, so removable
+    // singularities like the one of sin(x) / x at 0 are left out. The numerator only counts
+    // as vanishing if Newton's method on it settles next to the root, since a small value
+    // alone can't tell 1e-12 / x from 0 / x; multiple roots converge slowly, hence the sqrt
+    pub fn poles(&self, a: f64, b: f64, samples: usize, tol: f64) -> Vec<f64> {
+
+        let (top, bottom) = (self.numerator(), self.denominator());
+        let top_diff = top.diff();
+
+        let removable = |pole: f64| match newton(&*top, &*top_diff, pole, NEWTON_MAX_ITER, tol) {
+
+            Some(root) => (root - pole).abs() <= tol.sqrt(),
+            None => false,
+        };
+
+        bottom.all_roots(a, b, samples, tol).into_iter().filter(|pole| !removable(*pole)).collect()
+    }
+
+    // For a simple pole p with f = N / D, (x - p) N / D is 0 / 0 at p, so one application
+    // of L'Hôpital's rule on the symbolic derivatives gives the limit
+    pub fn residue_at(&self, pole: f64) -> f64 {
+
+        let shift = UnaryFunction::Id.new().sub(UnaryFunction::Const(pole).new());
+        let top = shift.mul(self.numerator());
+
+        top.diff().eval(&pole) / self.denominator().diff().eval(&pole)
+    }
+
+    pub fn structurally_equals(&self, other: &dyn FunctionTrait) -> bool {
+
+        match (Shape::of(self), Shape::of(other)) {
+
+            (Shape::Unary(l), Shape::Unary(r)) => l == r,
+            (Shape::Variable(l), Shape::Variable(r)) => l == r,
+            (Shape::Clamp(l, l_lo, l_hi), Shape::Clamp(r, r_lo, r_hi)) => l_lo == r_lo && l_hi == r_hi && l.structurally_equals(&*r),
+            (Shape::Piecewise(l_t, l_b, l_a), Shape::Piecewise(r_t, r_b, r_a)) => l_t == r_t && l_b.structurally_equals(&*r_b) && l_a.structurally_equals(&*r_a),
+            (Shape::Opaque(l), Shape::Opaque(r)) => Rc::ptr_eq(&l.eval_fn, &r.eval_fn),
+            (Shape::Power(l, l_power), Shape::Power(r, r_power)) => l_power == r_power && l.structurally_equals(&*r),
+            (Shape::Polynomial(l), Shape::Polynomial(r)) => l == r,
+
+            // Neither caching nor naming changes what a function is
+            (Shape::Memo(memo), _) => memo.inner.structurally_equals(other),
+            (_, Shape::Memo(memo)) => self.structurally_equals(&*memo.inner),
+            (Shape::Named(_, inner), _) => inner.structurally_equals(other),
+            (_, Shape::Named(_, inner)) => self.structurally_equals(&*inner),
+
+            #[cfg(feature = "units")]
+            (Shape::Unit(l, l_unit), Shape::Unit(r, r_unit)) => l_unit == r_unit && l.structurally_equals(&*r),
+
+            (l_shape, r_shape) => {
+
+                let same_kind = std::mem::discriminant(&l_shape) == std::mem::discriminant(&r_shape);
+
+                same_kind && l_shape.children().iter().zip(r_shape.children()).all(|(l, r)| l.structurally_equals(&**r))
+            },
+        }
+    }
+
+    pub fn match_pattern(&self, pattern: &Pattern) -> Option<HashMap<String, Function>> {
+
+        let mut bindings = HashMap::new();
+
+        if match_into(&self.to_function(), pattern, &mut bindings) { Some(bindings) } else { None }
+    }
+
+    // A rule whose right side needs a wildcard its left side never binds is skipped
+    pub fn rewrite(&self, rules: &[Rule]) -> Function {
+
+        let rewritten = Shape::of(self).map(|child| child.rewrite(rules));
+
+        for rule in rules {
+
+            if let Some(replacement) = rewritten.match_pattern(&rule.lhs).and_then(|bindings| rule.rhs.instantiate(&bindings)) {
+
+                return replacement;
+            }
+        }
+
+        rewritten
+    }
+
+    // Forward error estimate where every rounded operation contributes half an ulp of its result
+    // and input errors are propagated through each node's local derivative, infinite if a
+    // denominator can't be told apart from zero
+    pub fn eval_with_error(&self, x: f64) -> (f64, f64) {
+
+        eval_with_error(self, x, 0.0)
+    }
+
+    // Returns (f, f', f'') from a single pass over second-order jets, only the leaves
+    // are differentiated symbolically
+    pub fn eval_with_derivatives_2(&self, x: f64) -> (f64, f64, f64) {
+
+        eval_jet(self, (x, 1.0, 0.0))
+    }
+
+    // Evaluates in post-order and records one line per operation, like "sin(2.0) = 0.909..."
+    // or "0.909... / 2.0 = 0.454...", leaves and compositions themselves are silent
+    pub fn eval_trace(&self, x: f64) -> (f64, Vec<String>) {
+
+        let mut steps = Vec::new();
+        let value = eval_trace(self, x, &mut steps);
+
+        (value, steps)
+    }
+
+    // Every intermediate that overflows is pulled back to f64::MAX or f64::MIN by its sign,
+    // NaN included, so nothing non-finite ever reaches the next node
+    pub fn eval_saturating(&self, x: &f64) -> f64 {
+
+        match eval_guarded(self, *x, &|_, value| Ok::<f64, std::convert::Infallible>(saturate(value))) {
+
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    // Applies policy to the value of every node that comes out non-finite, an error names
+    // the first such node in evaluation order
+    pub fn eval_policy(&self, x: &f64, policy: NanPolicy) -> Result<f64, EvalError> {
+
+        eval_guarded(self, *x, &|node, value| match policy {
+
+            _ if value.is_finite() => Ok(value),
+            NanPolicy::Propagate => Ok(value),
+            NanPolicy::ZeroFill => Ok(0.0),
+            NanPolicy::Error => Err(EvalError::NonFinite(node.to_string(), value)),
+        })
+    }
+
+    pub fn is_zero(&self) -> bool {
+
+        const_value(self) == Some(0.0)
+    }
+
+    pub fn eval_slice(&self, xs: &[f64]) -> Vec<f64> {
+
+        xs.iter().map(|x| self.eval(x)).collect()
+    }
+
+    // None marks the non-finite values, for callers that want to leave a gap there
+    pub fn eval_slice_interpolated(&self, xs: &[f64]) -> Vec<Option<f64>> {
+
+        xs.iter().map(|x| Some(self.eval(x)).filter(|value| value.is_finite())).collect()
+    }
+
+    // Flattens the top-level sums and differences and adds the terms with Neumaier's
+    // variant of Kahan summation, which also survives terms larger than the running sum
+    pub fn eval_accurate(&self, x: f64) -> f64 {
+
+        let mut terms = Vec::new();
+        push_signed_terms(self.to_function(), 1.0, &mut terms);
+
+        let (sum, compensation) = terms.iter().fold((0.0, 0.0), |(sum, compensation): (f64, f64), (term, sign)| {
+
+            let value = sign * term.eval(&x);
+            let next = sum + value;
+
+            let lost = if sum.abs() >= value.abs() { (sum - next) + value } else { (value - next) + sum };
+
+            (next, compensation + lost)
+        });
+
+        sum + compensation
+    }
+
+    pub fn eval_env_slice(&self, envs: &[HashMap<String, f64>]) -> Vec<f64> {
+
+        envs.iter().map(|env| self.eval_env(env)).collect()
+    }
+
+    pub fn eval_into(&self, xs: &[f64], out: &mut [f64]) {
+
+        assert_eq!(xs.len(), out.len(), "Output buffer must match the input length!");
+
+        for (x, value) in xs.iter().zip(out.iter_mut()) {
+
+            *value = self.eval(x);
+        }
+    }
+
+    // The end of the period is left out since it repeats the first sample, as FFTs expect
+    pub fn sample_uniform(&self, start: f64, period: f64, n: usize) -> Vec<f64> {
+
+        (0..n).map(|i| self.eval(&(start + period * i as f64 / n as f64))).collect()
+    }
+
+    // Samples at the Chebyshev-Gauss-Lobatto nodes -cos(πk / (n - 1)) mapped onto [a, b], in
+    // increasing order and including both endpoints, a single node sits at the midpoint
+    pub fn sample_chebyshev(&self, a: f64, b: f64, n: usize) -> Vec<(f64, f64)> {
+
+        let node = |k: usize| if n == 1 { 0.0 } else { -(std::f64::consts::PI * k as f64 / (n - 1) as f64).cos() };
+
+        (0..n).map(|k| (a + b) / 2.0 + (b - a) / 2.0 * node(k)).map(|x| (x, self.eval(&x))).collect()
+    }
+
+    // Row j holds the values along x at the j-th y, both ranges sampled inclusively like
+    // the one dimensional grids
+    pub fn sample_grid(&self, x_range: (f64, f64), y_range: (f64, f64), nx: usize, ny: usize) -> Vec<Vec<f64>> {
+
+        grid(y_range.0, y_range.1, ny).map(|y| {
+
+            grid(x_range.0, x_range.1, nx).map(|x| self.eval_env(&HashMap::from([(String::from("x"), x), (String::from("y"), y)]))).collect()
+
+        }).collect()
+    }
+
+    // Angular frequency (the k in sin(kx)) of the strongest non-constant DFT bin over
+    // [0, period), so only whole numbers of cycles per period can come out
+    pub fn dominant_frequency(&self, period: f64, samples: usize) -> f64 {
+
+        let values = self.sample_uniform(0.0, period, samples);
+
+        let magnitude = |k: usize| {
+
+            let (re, im) = values.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, value)| {
+
+                let angle = -2.0 * std::f64::consts::PI * (k * n) as f64 / samples as f64;
+
+                (re + value * angle.cos(), im + value * angle.sin())
+            });
+
+            re * re + im * im
+        };
+
+        let best = (1..=samples / 2).map(|k| (k, magnitude(k))).fold((0, 0.0), |best, bin| if bin.1 > best.1 { bin } else { best });
+
+        2.0 * std::f64::consts::PI * best.0 as f64 / period
+    }
+
+    // Functions aren't thread safe, so this evaluates a flattened copy of the tree instead,
+    // falling back to eval_slice when that isn't possible (free variables, opaque or memoised functions)
+    #[cfg(feature = "parallel")]
+    pub fn eval_par(&self, xs: &[f64]) -> Vec<f64> {
+
+        match self.to_program() {
+
+            Some(program) => xs.par_iter().map(|x| run_program(&program, *x)).collect(),
+            None => self.eval_slice(xs),
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn to_program(&self) -> Option<Vec<Op>> {
+
+        let mut program = Vec::new();
+
+        if push_ops(self, &mut program) { Some(program) } else { None }
+    }
+
+    // Opaque functions have no token form, so this is None if it meets one
+    pub fn to_prefix(&self) -> Option<Vec<Token>> {
+
+        let mut tokens = Vec::new();
+
+        if push_prefix_tokens(self, &mut tokens) { Some(tokens) } else { None }
+    }
+
+    // The body of an (f64) -> f64 function, locals and the final end included but not the
+    // size prefix, calling the primitives as imports numbered in WASM_IMPORTS order. Free
+    // variables and opaque functions can't be compiled, so this is None if it meets one
+    pub fn to_wasm_func(&self) -> Option<Vec<u8>> {
+
+        let mut code = Vec::new();
+        let mut locals = 1;
+
+        if !push_wasm(self, 0, &mut locals, &mut code) {
+
+            return None;
+        }
+
+        code.push(WASM_END);
+
+        let mut body = Vec::new();
+
+        if locals > 1 {
+
+            body.push(1);
+            push_leb128(&mut body, locals - 1);
+            body.push(WASM_F64);
+        } else {
+
+            body.push(0);
+        }
+
+        body.extend(code);
+        Some(body)
+    }
+
+    // Display writes the input as $ so compositions can substitute into it, this spells it
+    // as var instead
+    pub fn to_string_in(&self, var: &str) -> String {
+
+        self.to_string().replace('$', var)
+    }
+
+    pub fn pretty_tree(&self) -> String {
+
+        let mut lines = Vec::new();
+        push_tree_lines(self, 0, &mut lines);
+
+        lines.join("\n")
+    }
+
+    pub fn to_dot(&self) -> String {
+
+        let mut lines = vec![String::from("digraph {")];
+        push_dot_lines(self, &mut 0, &mut lines);
+        lines.push(String::from("}"));
+
+        lines.join("\n")
+    }
+
+    // The y range is fitted to the finite samples, and the line restarts after any that aren't
+    pub fn to_postscript(&self, a: f64, b: f64, samples: usize, width: f64, height: f64) -> String {
+
+        let mut lines = vec![String::from("%!PS-Adobe-3.0"), format!("%%BoundingBox: 0 0 {} {}", width.ceil(), height.ceil()), String::from("newpath")];
+        let mut drawing = false;
+
+        for point in self.plot_points(a, b, samples, width, height) {
+
+            if let Some((px, py)) = point {
+
+                lines.push(format!("{:.3} {:.3} {}", px, py, if drawing { "lineto" } else { "moveto" }));
+            }
+
+            drawing = point.is_some();
+        }
+
+        lines.push(String::from("stroke"));
+        lines.push(String::from("showpage"));
+
+        lines.join("\n")
+    }
+
+    // One row per step boundary from a to b inclusive, the derivative is only built once
+    pub fn tabulate(&self, a: f64, b: f64, steps: usize) -> String {
+
+        let derivative = self.diff();
+        let mut lines = vec![format!("{:>14} {:>14} {:>14}", "x", "f(x)", "f'(x)")];
+
+        for i in 0..=steps {
+
+            let x = if steps == 0 { a } else { a + (b - a) * i as f64 / steps as f64 };
+
+            lines.push(format!("{:>14.6} {:>14.6} {:>14.6}", x, self.eval(&x), derivative.eval(&x)));
+        }
+
+        lines.join("\n")
+    }
+
+    // A full <svg> document around a single path, SVG's y axis points down so it's flipped
+    pub fn to_svg_path(&self, a: f64, b: f64, samples: usize, width: u32, height: u32) -> String {
+
+        let mut commands = Vec::new();
+        let mut drawing = false;
+
+        for point in self.plot_points(a, b, samples, width as f64, height as f64) {
+
+            if let Some((px, py)) = point {
+
+                commands.push(format!("{}{:.3},{:.3}", if drawing { "L" } else { "M" }, px, height as f64 - py));
+            }
+
+            drawing = point.is_some();
+        }
+
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\"><path d=\"{d}\" fill=\"none\" stroke=\"black\"/></svg>",
+            w = width, h = height, d = commands.join(" "),
+        )
+    }
+
+    // Samples evenly from a to b inclusive, scaled into a width by height canvas with
+    // y pointing up, non-finite values become None
+    fn plot_points(&self, a: f64, b: f64, samples: usize, width: f64, height: f64) -> Vec<Option<(f64, f64)>> {
+
+        assert!(samples >= 2, "Need at least two samples to plot!");
+
+        let xs: Vec<f64> = (0..samples).map(|i| a + (b - a) * i as f64 / (samples - 1) as f64).collect();
+        let ys = self.eval_slice(&xs);
+
+        let finite = ys.iter().cloned().filter(|y| y.is_finite());
+        let lo = finite.clone().fold(f64::INFINITY, f64::min);
+        let hi = finite.fold(f64::NEG_INFINITY, f64::max);
+        let (lo, hi) = if lo < hi { (lo, hi) } else { (lo - 1.0, lo + 1.0) };
+
+        xs.iter().zip(ys).map(|(x, y)| {
+
+            if y.is_finite() { Some(((x - a) / (b - a) * width, (y - lo) / (hi - lo) * height)) } else { None }
+
+        }).collect()
+    }
+
+    pub fn numeric_diff(&self, x: f64) -> f64 {
+
+        central_difference(|t| self.eval(&t), x)
+    }
+
+    pub fn curvature(&self, x: f64) -> f64 {
+
+        let first = self.diff();
+        let second = first.diff();
+
+        let slope = first.eval(&x);
+
+        second.eval(&x).abs() / (1.0 + slope * slope).powf(1.5)
+    }
+
+    pub fn secant_root(&self, x0: f64, x1: f64, max_iter: usize, tol: f64) -> Option<f64> {
+
+        let (mut x_prev, mut x) = (x0, x1);
+        let (mut f_prev, mut f_x) = (self.eval(&x0), self.eval(&x1));
+
+        for _ in 0..max_iter {
+
+            if f_x == 0.0 {
+
+                return Some(x);
+            }
+
+            if f_x == f_prev {
+
+                return None;
+            }
+
+            let step = f_x * (x - x_prev) / (f_x - f_prev);
+
+            x_prev = x;
+            f_prev = f_x;
+            x -= step;
+            f_x = self.eval(&x);
+
+            if !x.is_finite() {
+
+                return None;
+            }
+
+            if step.abs() < tol {
+
+                return Some(x);
+            }
+        }
+
+        None
+    }
+
+    pub fn bisect_root(&self, a: f64, b: f64, tol: f64) -> Option<f64> {
+
+        let (mut lo, mut hi) = (a, b);
+        let (f_lo, f_hi) = (self.eval(&lo), self.eval(&hi));
+
+        if f_lo == 0.0 {
+
+            return Some(lo);
+        }
+
+        if f_hi == 0.0 {
+
+            return Some(hi);
+        }
+
+        if f_lo.signum() == f_hi.signum() || f_lo.is_nan() || f_hi.is_nan() {
+
+            return None;
+        }
+
+        let lo_sign = f_lo.signum();
+
+        while (hi - lo).abs() > tol {
+
+            let mid = (lo + hi) / 2.0;
+
+            // Stop once the bracket can't shrink any further in floating point
+            if mid == lo || mid == hi {
+
+                break;
+            }
+
+            let f_mid = self.eval(&mid);
+
+            if f_mid == 0.0 {
+
+                return Some(mid);
+            }
+
+            if f_mid.signum() == lo_sign { lo = mid; } else { hi = mid; }
+        }
+
+        Some((lo + hi) / 2.0)
+    }
+
+    pub fn fixed_point(&self, guess: f64, max_iter: usize, tol: f64) -> Option<f64> {
+
+        let mut x = guess;
+
+        for _ in 0..max_iter {
+
+            let next = self.eval(&x);
+
+            if !next.is_finite() {
+
+                return None;
+            }
+
+            if (next - x).abs() < tol {
+
+                return Some(next);
+            }
+
+            x = next;
+        }
+
+        None
+    }
+
+    pub fn lipschitz_constant(&self, a: f64, b: f64, samples: usize) -> f64 {
+
+        let derivative = self.diff();
+
+        grid(a, b, samples).map(|x| derivative.eval(&x).abs()).fold(0.0, f64::max)
+    }
+
+    // The extremum over [a, b] is at an endpoint or a critical point, so those are the only
+    // places evaluated. Critical points are found from `samples` Newton starts on the derivative
+    pub fn maximize(&self, a: f64, b: f64, samples: usize) -> (f64, f64) {
+
+        self.extremum(a, b, samples, |candidate, best| candidate > best)
+    }
+
+    pub fn minimize(&self, a: f64, b: f64, samples: usize) -> (f64, f64) {
+
+        self.extremum(a, b, samples, |candidate, best| candidate < best)
+    }
+
+    fn extremum(&self, a: f64, b: f64, samples: usize, better: fn(f64, f64) -> bool) -> (f64, f64) {
+
+        let critical_points = self.diff().all_roots(a, b, samples, CRITICAL_POINT_TOL);
+        let candidates = critical_points.into_iter().map(|x| x.clamp(a, b)).chain([a, b]);
+
+        candidates.map(|x| (x, self.eval(&x))).fold((a, self.eval(&a)), |best, candidate| if better(candidate.1, best.1) { candidate } else { best })
+    }
+
+    pub fn count_nodes(&self) -> usize {
+
+        let mut counter = NodeCounter { count: 0 };
+        self.accept(&mut counter);
+
+        counter.count
+    }
+
+    // Subtractions count as additions, and opaque functions as transcendental calls since
+    // there's no telling what they cost
+    pub fn operation_counts(&self) -> OpCounts {
+
+        let mut counts = OpCounts::default();
+        count_operations(self, &mut counts);
+
+        counts
+    }
+
+    // Named after the libm intrinsics, opaque functions aren't included since they bring
+    // their own implementation
+    pub fn primitives_used(&self) -> HashSet<&'static str> {
+
+        let mut primitives = HashSet::new();
+        collect_primitives(self, &mut primitives);
+
+        primitives
+    }
+
+    pub fn memory_footprint(&self) -> usize {
+
+        let mut seen = HashSet::new();
+        seen.insert(self as *const dyn FunctionTrait as *const ());
+
+        footprint_of(self, &mut seen)
+    }
+}
+
+#[cfg(feature = "rational")]
+impl dyn FunctionTrait {
+
+    pub fn eval_rational(&self, x: Ratio<i64>) -> Option<Ratio<i64>> {
+
+        match Shape::of(self) {
+
+            Shape::Unary(UnaryFunction::Const(c)) => to_exact_ratio(c),
+            Shape::Unary(UnaryFunction::Id) => Some(x),
+            Shape::Unary(_) => None,
+            Shape::Variable(ref name) if name == "x" => Some(x),
+            Shape::Variable(_) => None,
+
+            Shape::Sum(left, right) => left.eval_rational(x)?.checked_add(&right.eval_rational(x)?),
+            Shape::Difference(left, right) => left.eval_rational(x)?.checked_sub(&right.eval_rational(x)?),
+            Shape::Negative(source) => source.eval_rational(x).map(|value| -value),
+            Shape::Product(left, right) => left.eval_rational(x)?.checked_mul(&right.eval_rational(x)?),
+            Shape::Quotient(top, bottom) => top.eval_rational(x)?.checked_div(&bottom.eval_rational(x)?),
+            Shape::Composed(source, target) => source.eval_rational(target.eval_rational(x)?),
+
+            Shape::Clamp(source, lo, hi) => {
+
+                let value = source.eval_rational(x)?;
+
+                Some(num_traits::clamp(value, to_exact_ratio(lo)?, to_exact_ratio(hi)?))
+            },
+
+            Shape::Piecewise(threshold, below, above) => {
+
+                if x < to_exact_ratio(threshold)? { below.eval_rational(x) } else { above.eval_rational(x) }
+            },
+
+            Shape::Opaque(_) => None,
+
+            Shape::Power(base, power) => {
+
+                let value = base.eval_rational(x)?;
+                let magnitude = (0..power.unsigned_abs()).try_fold(Ratio::from_integer(1), |acc, _| acc.checked_mul(&value))?;
+
+                if power < 0 { Ratio::from_integer(1).checked_div(&magnitude) } else { Some(magnitude) }
+            },
+
+            Shape::Polynomial(coeffs) => polynomial_tree(&coeffs).eval_rational(x),
+            Shape::Memo(memo) => memo.inner.eval_rational(x),
+            Shape::Named(_, inner) => inner.eval_rational(x),
+            #[cfg(feature = "units")]
+            Shape::Unit(inner, _) => inner.eval_rational(x),
+        }
+    }
+}
+
+#[cfg(feature = "rational")]
+fn to_exact_ratio(value: f64) -> Option<Ratio<i64>> {
+
+    Ratio::approximate_float(value).filter(|ratio: &Ratio<i64>| *ratio.numer() as f64 / *ratio.denom() as f64 == value)
+}
+
+#[cfg(feature = "bigdecimal")]
+impl dyn FunctionTrait {
+
+    // Sums, differences and products are exact, quotients and negative powers are rounded
+    // to precision significant digits. None for transcendental primitives, free variables,
+    // division by zero and a precision of zero
+    pub fn eval_bigdecimal(&self, x: &BigDecimal, precision: u32) -> Option<BigDecimal> {
+
+        let context = Context::default().with_prec(precision)?;
+        let eval = |function: &Function| function.eval_bigdecimal(x, precision);
+
+        match Shape::of(self) {
+
+            Shape::Unary(UnaryFunction::Const(c)) => to_exact_decimal(c),
+            Shape::Unary(UnaryFunction::Id) => Some(x.clone()),
+            Shape::Unary(_) => None,
+            Shape::Variable(ref name) if name == "x" => Some(x.clone()),
+            Shape::Variable(_) => None,
+
+            Shape::Sum(left, right) => Some(eval(&left)? + eval(&right)?),
+            Shape::Difference(left, right) => Some(eval(&left)? - eval(&right)?),
+            Shape::Negative(source) => Some(-eval(&source)?),
+            Shape::Product(left, right) => Some(eval(&left)? * eval(&right)?),
+
+            Shape::Quotient(top, bottom) => {
+
+                let bottom = eval(&bottom)?;
+
+                if bottom.is_zero() { None } else { Some(context.round_decimal(eval(&top)? * bottom.inverse_with_context(&context))) }
+            },
+
+            Shape::Composed(source, target) => source.eval_bigdecimal(&eval(&target)?, precision),
+
+            Shape::Clamp(source, lo, hi) => {
+
+                let (value, lo, hi) = (eval(&source)?, to_exact_decimal(lo)?, to_exact_decimal(hi)?);
+
+                Some(if value < lo { lo } else if value > hi { hi } else { value })
+            },
+
+            Shape::Piecewise(threshold, below, above) => {
+
+                if *x < to_exact_decimal(threshold)? { eval(&below) } else { eval(&above) }
+            },
+
+            Shape::Opaque(_) => None,
+
+            Shape::Power(base, power) => {
+
+                let value = eval(&base)?;
+                let magnitude = (0..power.unsigned_abs()).fold(BigDecimal::from(1), |acc, _| acc * &value);
+
+                if power >= 0 { Some(magnitude) } else if magnitude.is_zero() { None } else { Some(magnitude.inverse_with_context(&context)) }
+            },
+
+            Shape::Polynomial(coeffs) => polynomial_tree(&coeffs).eval_bigdecimal(x, precision),
+            Shape::Memo(memo) => memo.inner.eval_bigdecimal(x, precision),
+            Shape::Named(_, inner) => inner.eval_bigdecimal(x, precision),
+            #[cfg(feature = "units")]
+            Shape::Unit(inner, _) => inner.eval_bigdecimal(x, precision),
+        }
+    }
+}
+
+// Every finite float is a terminating decimal, so this is exact
+#[cfg(feature = "bigdecimal")]
+fn to_exact_decimal(value: f64) -> Option<BigDecimal> {
+
+    BigDecimal::from_f64(value)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Token {
+
+    Unary(UnaryFunction),
+    Variable(String),
+    Add,
+    Sub,
+    Neg,
+    Mul,
+    Div,
+    Compose,
+    Clamp(f64, f64),
+    Piecewise(f64),
+    Power(i32),
+}
+
+impl Token {
+
+    fn arity(&self) -> usize {
+
+        match self {
+
+            Token::Unary(_) | Token::Variable(_) => 0,
+            Token::Neg | Token::Clamp(_, _) | Token::Power(_) => 1,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum TokenError {
+
+    MissingOperand,
+    TrailingTokens(usize),
+    FreeVariable(String),
+}
+
+impl fmt::Display for TokenError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        match self {
+
+            TokenError::MissingOperand => write!(f, "Token stream ended before an operand"),
+            TokenError::TrailingTokens(count) => write!(f, "{} tokens left after the expression", count),
+            TokenError::FreeVariable(name) => write!(f, "Cannot evaluate free variable {}", name),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+fn push_prefix_tokens(function: &dyn FunctionTrait, tokens: &mut Vec<Token>) -> bool {
+
+    let shape = Shape::of(function);
+
+    match &shape {
+
+        Shape::Polynomial(coeffs) => return push_prefix_tokens(&*polynomial_tree(coeffs), tokens),
+        Shape::Memo(memo) => return push_prefix_tokens(&*memo.inner, tokens),
+        Shape::Named(_, inner) => return push_prefix_tokens(&**inner, tokens),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => return push_prefix_tokens(&**inner, tokens),
+        _ => {},
+    }
+
+    tokens.push(match &shape {
+
+        Shape::Sum(_, _) => Token::Add,
+        Shape::Difference(_, _) => Token::Sub,
+        Shape::Negative(_) => Token::Neg,
+        Shape::Product(_, _) => Token::Mul,
+        Shape::Quotient(_, _) => Token::Div,
+        Shape::Composed(_, _) => Token::Compose,
+        Shape::Unary(unary) => Token::Unary(*unary),
+        Shape::Variable(name) => Token::Variable(name.clone()),
+        Shape::Clamp(_, lo, hi) => Token::Clamp(*lo, *hi),
+        Shape::Piecewise(threshold, _, _) => Token::Piecewise(*threshold),
+        Shape::Opaque(_) => return false,
+        Shape::Power(_, power) => Token::Power(*power),
+        Shape::Polynomial(_) | Shape::Memo(_) | Shape::Named(_, _) => unreachable!(),
+        #[cfg(feature = "units")]
+        Shape::Unit(_, _) => unreachable!(),
+    });
+
+    shape.children().into_iter().all(|child| push_prefix_tokens(&**child, tokens))
+}
+
+// Import order for to_wasm_func, each one an (f64) -> f64 function
+pub const WASM_IMPORTS: [&str; 8] = ["sin", "cos", "exp", "log", "tgamma", "digamma", "erf", "erfc"];
+
+const WASM_END: u8 = 0x0b;
+const WASM_F64: u8 = 0x7c;
+const WASM_CALL: u8 = 0x10;
+const WASM_SELECT: u8 = 0x1b;
+const WASM_LOCAL_GET: u8 = 0x20;
+const WASM_LOCAL_SET: u8 = 0x21;
+const WASM_F64_CONST: u8 = 0x44;
+const WASM_F64_LT: u8 = 0x63;
+const WASM_F64_NEG: u8 = 0x9a;
+const WASM_F64_ADD: u8 = 0xa0;
+const WASM_F64_SUB: u8 = 0xa1;
+const WASM_F64_MUL: u8 = 0xa2;
+const WASM_F64_DIV: u8 = 0xa3;
+const WASM_F64_MIN: u8 = 0xa4;
+const WASM_F64_MAX: u8 = 0xa5;
+
+fn push_leb128(code: &mut Vec<u8>, mut value: u32) {
+
+    loop {
+
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+
+            code.push(byte);
+            return;
+        }
+
+        code.push(byte | 0x80);
+    }
+}
+
+fn push_wasm_const(code: &mut Vec<u8>, value: f64) {
+
+    code.push(WASM_F64_CONST);
+    code.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_wasm_local(code: &mut Vec<u8>, op: u8, local: u32) {
+
+    code.push(op);
+    push_leb128(code, local);
+}
+
+// x lives in local x_local, compositions bind their target to a fresh local for the source
+fn push_wasm(function: &dyn FunctionTrait, x_local: u32, locals: &mut u32, code: &mut Vec<u8>) -> bool {
+
+    let (children, op) = match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => {
+
+            push_wasm_const(code, c);
+            return true;
+        },
+
+        Shape::Unary(UnaryFunction::Id) => {
+
+            push_wasm_local(code, WASM_LOCAL_GET, x_local);
+            return true;
+        },
+
+        Shape::Variable(ref name) if name == "x" => {
+
+            push_wasm_local(code, WASM_LOCAL_GET, x_local);
+            return true;
+        },
+
+        Shape::Unary(unary) => {
+
+            let name = intrinsic_name(unary).unwrap();
+            let index = WASM_IMPORTS.iter().position(|import| *import == name).unwrap();
+
+            push_wasm_local(code, WASM_LOCAL_GET, x_local);
+            code.push(WASM_CALL);
+            push_leb128(code, index as u32);
+
+            return true;
+        },
+
+        Shape::Sum(left, right) => (vec![left, right], WASM_F64_ADD),
+        Shape::Difference(left, right) => (vec![left, right], WASM_F64_SUB),
+        Shape::Product(left, right) => (vec![left, right], WASM_F64_MUL),
+        Shape::Quotient(top, bottom) => (vec![top, bottom], WASM_F64_DIV),
+        Shape::Negative(source) => (vec![source], WASM_F64_NEG),
+
+        Shape::Composed(source, target) => {
+
+            let local = *locals;
+            *locals += 1;
+
+            if !push_wasm(&*target, x_local, locals, code) {
+
+                return false;
+            }
+
+            push_wasm_local(code, WASM_LOCAL_SET, local);
+
+            return push_wasm(&*source, local, locals, code);
+        },
+
+        Shape::Clamp(source, lo, hi) => {
+
+            if !push_wasm(&*source, x_local, locals, code) {
+
+                return false;
+            }
+
+            push_wasm_const(code, lo);
+            code.push(WASM_F64_MAX);
+            push_wasm_const(code, hi);
+            code.push(WASM_F64_MIN);
+
+            return true;
+        },
+
+        // Both branches are pure, so evaluating both and selecting is fine
+        Shape::Piecewise(threshold, below, above) => {
+
+            if !push_wasm(&*below, x_local, locals, code) || !push_wasm(&*above, x_local, locals, code) {
+
+                return false;
+            }
+
+            push_wasm_local(code, WASM_LOCAL_GET, x_local);
+            push_wasm_const(code, threshold);
+            code.push(WASM_F64_LT);
+            code.push(WASM_SELECT);
+
+            return true;
+        },
+
+        Shape::Power(base, power) => {
+
+            let local = *locals;
+            *locals += 1;
+
+            if power < 0 {
+
+                push_wasm_const(code, 1.0);
+            }
+
+            if !push_wasm(&*base, x_local, locals, code) {
+
+                return false;
+            }
+
+            push_wasm_local(code, WASM_LOCAL_SET, local);
+            push_wasm_const(code, 1.0);
+
+            for _ in 0..power.unsigned_abs() {
+
+                push_wasm_local(code, WASM_LOCAL_GET, local);
+                code.push(WASM_F64_MUL);
+            }
+
+            if power < 0 {
+
+                code.push(WASM_F64_DIV);
+            }
+
+            return true;
+        },
+
+        Shape::Variable(_) | Shape::Opaque(_) => return false,
+        Shape::Polynomial(coeffs) => return push_wasm(&*polynomial_tree(&coeffs), x_local, locals, code),
+        Shape::Memo(memo) => return push_wasm(&*memo.inner, x_local, locals, code),
+        Shape::Named(_, inner) => return push_wasm(&*inner, x_local, locals, code),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => return push_wasm(&*inner, x_local, locals, code),
+    };
+
+    let pushed = children.iter().all(|child| push_wasm(&**child, x_local, locals, code));
+    code.push(op);
+
+    pushed
+}
+
+pub fn eval_tokens(tokens: &[Token], x: f64) -> Result<f64, TokenError> {
+
+    let (value, end) = eval_prefix(tokens, 0, x)?;
+
+    if end < tokens.len() { Err(TokenError::TrailingTokens(tokens.len() - end)) } else { Ok(value) }
+}
+
+fn token_at(tokens: &[Token], pos: usize) -> Result<&Token, TokenError> {
+
+    tokens.get(pos).ok_or(TokenError::MissingOperand)
+}
+
+fn skip_prefix(tokens: &[Token], start: usize) -> Result<usize, TokenError> {
+
+    (0..token_at(tokens, start)?.arity()).try_fold(start + 1, |pos, _| skip_prefix(tokens, pos))
+}
+
+fn eval_prefix(tokens: &[Token], start: usize, x: f64) -> Result<(f64, usize), TokenError> {
+
+    Ok(match token_at(tokens, start)? {
+
+        Token::Unary(unary) => (unary.eval(&x), start + 1),
+        Token::Variable(name) if name == "x" => (x, start + 1),
+        Token::Variable(name) => return Err(TokenError::FreeVariable(name.clone())),
+
+        Token::Neg => {
+
+            let (value, end) = eval_prefix(tokens, start + 1, x)?;
+            (-value, end)
+        },
+
+        Token::Clamp(lo, hi) => {
+
+            let (value, end) = eval_prefix(tokens, start + 1, x)?;
+            (value.clamp(*lo, *hi), end)
+        },
+
+        Token::Power(power) => {
+
+            let (value, end) = eval_prefix(tokens, start + 1, x)?;
+            (value.powi(*power), end)
+        },
+
+        // The source comes first but needs the target's value as its x
+        Token::Compose => {
+
+            let target_start = skip_prefix(tokens, start + 1)?;
+            let (inner, end) = eval_prefix(tokens, target_start, x)?;
+            let (value, _) = eval_prefix(tokens, start + 1, inner)?;
+
+            (value, end)
+        },
+
+        binary => {
+
+            let (left, mid) = eval_prefix(tokens, start + 1, x)?;
+            let (right, end) = eval_prefix(tokens, mid, x)?;
+
+            let value = match binary {
+
+                Token::Add => left + right,
+                Token::Sub => left - right,
+                Token::Mul => left * right,
+                Token::Div => left / right,
+                Token::Piecewise(threshold) => if x < *threshold { left } else { right },
+                _ => unreachable!(),
+            };
+
+            (value, end)
+        },
+    })
+}
+
+fn push_tree_lines(function: &dyn FunctionTrait, depth: usize, lines: &mut Vec<String>) {
+
+    let shape = Shape::of(function);
+    lines.push(format!("{}{}", "  ".repeat(depth), shape.label()));
+
+    for child in shape.children() {
+
+        push_tree_lines(&**child, depth + 1, lines);
+    }
+}
+
+fn push_dot_lines(function: &dyn FunctionTrait, counter: &mut usize, lines: &mut Vec<String>) -> usize {
+
+    let id = *counter;
+    *counter += 1;
+
+    let shape = Shape::of(function);
+    lines.push(format!("    n{} [label=\"{}\"];", id, shape.symbol()));
+
+    for child in shape.children() {
+
+        let child_id = push_dot_lines(&**child, counter, lines);
+        lines.push(format!("    n{} -> n{};", id, child_id));
+    }
+
+    id
+}
+
+#[cfg(feature = "parallel")]
+#[derive(Copy, Clone)]
+enum Op {
+
+    Unary(UnaryFunction),
+    Add,
+    Sub,
+    Neg,
+    Mul,
+    Div,
+    BindX,
+    UnbindX,
+    Clamp(f64, f64),
+    Select(f64),
+    Powi(i32),
+}
+
+#[cfg(feature = "parallel")]
+fn push_ops(function: &dyn FunctionTrait, program: &mut Vec<Op>) -> bool {
+
+    let (children, op) = match Shape::of(function) {
+
+        Shape::Unary(unary) => (vec![], Op::Unary(unary)),
+        Shape::Variable(ref name) if name == "x" => (vec![], Op::Unary(UnaryFunction::Id)),
+        Shape::Sum(left, right) => (vec![left, right], Op::Add),
+        Shape::Difference(left, right) => (vec![left, right], Op::Sub),
+        Shape::Negative(source) => (vec![source], Op::Neg),
+        Shape::Product(left, right) => (vec![left, right], Op::Mul),
+        Shape::Quotient(top, bottom) => (vec![top, bottom], Op::Div),
+        Shape::Clamp(source, lo, hi) => (vec![source], Op::Clamp(lo, hi)),
+        Shape::Piecewise(threshold, below, above) => (vec![below, above], Op::Select(threshold)),
+        Shape::Power(base, power) => (vec![base], Op::Powi(power)),
+
+        Shape::Composed(source, target) => {
+
+            if !push_ops(&*target, program) {
+
+                return false;
+            }
+
+            program.push(Op::BindX);
+            let pushed = push_ops(&*source, program);
+            program.push(Op::UnbindX);
+
+            return pushed;
+        },
+
+        // The cache isn't thread safe, so a memoised function is evaluated serially through it
+        Shape::Variable(_) | Shape::Opaque(_) | Shape::Memo(_) => return false,
+        Shape::Named(_, inner) => return push_ops(&*inner, program),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => return push_ops(&*inner, program),
+        Shape::Polynomial(coeffs) => return push_ops(&*polynomial_tree(&coeffs), program),
+    };
+
+    let pushed = children.iter().all(|child| push_ops(&**child, program));
+    program.push(op);
+
+    pushed
+}
+
+#[cfg(feature = "parallel")]
+fn run_program(program: &[Op], x: f64) -> f64 {
+
+    let mut stack: Vec<f64> = Vec::new();
+    let mut xs = vec![x];
+
+    for op in program {
+
+        let current_x = *xs.last().unwrap();
+
+        match *op {
+
+            Op::Unary(unary) => stack.push(unary.eval(&current_x)),
+            Op::Neg => { let value = stack.pop().unwrap(); stack.push(-value); },
+            Op::Clamp(lo, hi) => { let value = stack.pop().unwrap(); stack.push(value.clamp(lo, hi)); },
+            Op::Powi(power) => { let value = stack.pop().unwrap(); stack.push(value.powi(power)); },
+            Op::BindX => xs.push(stack.pop().unwrap()),
+            Op::UnbindX => { xs.pop(); },
+
+            binary => {
+
+                let right = stack.pop().unwrap();
+                let left = stack.pop().unwrap();
+
+                stack.push(match binary {
+
+                    Op::Add => left + right,
+                    Op::Sub => left - right,
+                    Op::Mul => left * right,
+                    Op::Div => left / right,
+                    Op::Select(threshold) => if current_x < threshold { left } else { right },
+                    _ => unreachable!(),
+                });
+            },
+        }
+    }
+
+    stack.pop().unwrap()
+}
+
+fn inverse_of(function: &dyn FunctionTrait) -> Option<Function> {
+
+    let x = || UnaryFunction::Id.new();
+    let constant = |c: f64| UnaryFunction::Const(c).new();
+
+    if let Some((m, c)) = affine_of(function) {
+
+        return if m == 0.0 { None } else { Some(x().sub(constant(c)).div(constant(m))) };
+    }
+
+    // Each case undoes the outermost step and hands the rest to the inverse of the remainder
+    let (rest, undo) = match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Exp) => return Some(UnaryFunction::Log.new()),
+        Shape::Unary(UnaryFunction::Log) => return Some(UnaryFunction::Exp.new()),
+        Shape::Composed(source, target) => return Some(inverse_of(&*target)?.of(inverse_of(&*source)?)),
+
+        Shape::Negative(source) => (source, x().neg()),
+
+        Shape::Sum(left, right) => match (const_value(&*left), const_value(&*right)) {
+
+            (Some(c), _) => (right, x().sub(constant(c))),
+            (_, Some(c)) => (left, x().sub(constant(c))),
+            _ => return None,
+        },
+
+        Shape::Difference(left, right) => match (const_value(&*left), const_value(&*right)) {
+
+            (Some(c), _) => (right, constant(c).sub(x())),
+            (_, Some(c)) => (left, x().add(constant(c))),
+            _ => return None,
+        },
+
+        Shape::Product(left, right) => match (const_value(&*left), const_value(&*right)) {
+
+            (Some(c), _) if c != 0.0 => (right, x().div(constant(c))),
+            (_, Some(c)) if c != 0.0 => (left, x().div(constant(c))),
+            _ => return None,
+        },
+
+        Shape::Quotient(top, bottom) => match (const_value(&*top), const_value(&*bottom)) {
+
+            (Some(c), _) if c != 0.0 => (bottom, constant(c).div(x())),
+            (_, Some(c)) if c != 0.0 => (top, constant(c).mul(x())),
+            _ => return None,
+        },
+
+        _ => return None,
+    };
+
+    Some(inverse_of(&*rest)?.of(undo))
+}
+
+fn collect_variables(function: &dyn FunctionTrait, names: &mut BTreeSet<String>) {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(_)) => {},
+        Shape::Unary(_) | Shape::Opaque(_) => { names.insert(String::from("x")); },
+        Shape::Variable(name) => { names.insert(name); },
+        Shape::Polynomial(coeffs) => if trim_polynomial(coeffs).len() > 1 { names.insert(String::from("x")); },
+
+        // The source's x is the target, so it only contributes its other variables
+        Shape::Composed(source, target) => {
+
+            let mut source_names = BTreeSet::new();
+            collect_variables(&*source, &mut source_names);
+            source_names.remove("x");
+
+            names.extend(source_names);
+            collect_variables(&*target, names);
+        },
+
+        Shape::Piecewise(_, below, above) => {
+
+            names.insert(String::from("x"));
+            collect_variables(&*below, names);
+            collect_variables(&*above, names);
+        },
+
+        shape => for child in shape.children() {
+
+            collect_variables(&**child, names);
+        },
+    }
+}
+
+fn push_signed_terms(function: Function, sign: f64, terms: &mut Vec<(Function, f64)>) {
+
+    match Shape::of(&*function) {
+
+        Shape::Sum(left, right) => {
+
+            push_signed_terms(left, sign, terms);
+            push_signed_terms(right, sign, terms);
+        },
+
+        Shape::Difference(left, right) => {
+
+            push_signed_terms(left, sign, terms);
+            push_signed_terms(right, -sign, terms);
+        },
+
+        Shape::Negative(source) => push_signed_terms(source, -sign, terms),
+        _ => terms.push((function, sign)),
+    }
+}
+
+fn affine_form(m: f64, c: f64) -> Function {
+
+    let slope = match m {
+
+        1.0 => UnaryFunction::Id.new(),
+        _ => UnaryFunction::Const(m).new().mul(UnaryFunction::Id.new()),
+    };
+
+    match (m, c) {
+
+        (0.0, _) => UnaryFunction::Const(c).new(),
+        (_, 0.0) => slope,
+        _ if c < 0.0 => slope.sub(UnaryFunction::Const(-c).new()),
+        _ => slope.add(UnaryFunction::Const(c).new()),
+    }
+}
+
+fn depends_on(function: &dyn FunctionTrait, var: &str) -> bool {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(_)) => false,
+        Shape::Unary(_) | Shape::Opaque(_) | Shape::Piecewise(_, _, _) if var == "x" => true,
+        Shape::Unary(_) | Shape::Opaque(_) => false,
+        Shape::Variable(name) => name == var,
+        Shape::Polynomial(coeffs) => var == "x" && trim_polynomial(coeffs).len() > 1,
+
+        Shape::Composed(source, target) => {
+
+            let through_target = depends_on(&*target, var) && depends_on(&*source, "x");
+
+            through_target || (var != "x" && depends_on(&*source, var))
+        },
+
+        shape => shape.children().into_iter().any(|child| depends_on(&**child, var)),
+    }
+}
+
+fn polynomial_in(function: &dyn FunctionTrait, var: &str) -> bool {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(_)) | Shape::Unary(UnaryFunction::Id) | Shape::Variable(_) | Shape::Polynomial(_) => true,
+
+        Shape::Sum(left, right) | Shape::Difference(left, right) | Shape::Product(left, right) => {
+
+            polynomial_in(&*left, var) && polynomial_in(&*right, var)
+        },
+
+        Shape::Negative(source) => polynomial_in(&*source, var),
+        Shape::Memo(memo) => polynomial_in(&*memo.inner, var),
+        Shape::Named(_, inner) => polynomial_in(&*inner, var),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => polynomial_in(&*inner, var),
+        Shape::Quotient(top, bottom) => polynomial_in(&*top, var) && !depends_on(&*bottom, var),
+        Shape::Power(base, power) => (power >= 0 && polynomial_in(&*base, var)) || !depends_on(&*base, var),
+
+        // A polynomial of a polynomial is still one, the source's x being the target
+        Shape::Composed(source, target) => {
+
+            let inner = !depends_on(&*target, var) || (polynomial_in(&*source, "x") && polynomial_in(&*target, var));
+
+            inner && (var == "x" || polynomial_in(&*source, var))
+        },
+
+        _ => !depends_on(function, var),
+    }
+}
+
+fn find_missing_diff(function: &dyn FunctionTrait) -> Option<String> {
+
+    let shape = Shape::of(function);
+
+    match &shape {
+
+        Shape::Opaque(opaque) if !opaque.has_diff() => Some(String::from(opaque.name())),
+        _ => shape.children().into_iter().find_map(|child| find_missing_diff(&**child)),
+    }
+}
+
+fn intrinsic_name(unary: UnaryFunction) -> Option<&'static str> {
+
+    match unary {
+
+        UnaryFunction::Const(_) | UnaryFunction::Id => None,
+        UnaryFunction::Sin => Some("sin"),
+        UnaryFunction::Cos => Some("cos"),
+        UnaryFunction::Exp => Some("exp"),
+        UnaryFunction::Log => Some("log"),
+        UnaryFunction::Gamma => Some("tgamma"),
+        UnaryFunction::Digamma => Some("digamma"),
+        UnaryFunction::Erf => Some("erf"),
+        UnaryFunction::Erfc => Some("erfc"),
+    }
+}
+
+fn collect_primitives(function: &dyn FunctionTrait, primitives: &mut HashSet<&'static str>) {
+
+    let shape = Shape::of(function);
+
+    if let Some(name) = match shape { Shape::Unary(unary) => intrinsic_name(unary), _ => None } {
+
+        primitives.insert(name);
+    }
+
+    for child in shape.children() {
+
+        collect_primitives(&**child, primitives);
+    }
+}
+
+fn count_operations(function: &dyn FunctionTrait, counts: &mut OpCounts) {
+
+    let shape = Shape::of(function);
+
+    match shape {
+
+        Shape::Sum(_, _) | Shape::Difference(_, _) => counts.additions += 1,
+        Shape::Product(_, _) => counts.multiplications += 1,
+        Shape::Quotient(_, _) => counts.divisions += 1,
+        Shape::Unary(UnaryFunction::Const(_)) | Shape::Unary(UnaryFunction::Id) => {},
+        Shape::Unary(_) | Shape::Opaque(_) => counts.transcendentals += 1,
+
+        // Counted as Horner's method evaluates it
+        Shape::Polynomial(ref coeffs) => {
+
+            let degree = coeffs.iter().rposition(|c| *c != 0.0).unwrap_or(0);
+
+            counts.multiplications += degree;
+            counts.additions += degree;
+        },
+
+        // Counted as the repeated multiplication, and a reciprocal for negative powers
+        Shape::Power(_, power) => {
+
+            counts.multiplications += (power.unsigned_abs() as usize).saturating_sub(1);
+
+            if power < 0 {
+
+                counts.divisions += 1;
+            }
+        },
+
+        _ => {},
+    }
+
+    for child in shape.children() {
+
+        count_operations(&**child, counts);
+    }
+}
+
+struct NodeCounter {
+
+    count: usize,
+}
+
+impl NodeCounter {
+
+    fn visit_binary(&mut self, left: &Function, right: &Function) {
+
+        self.count += 1;
+        left.accept(self);
+        right.accept(self);
+    }
+}
+
+impl Visitor for NodeCounter {
+
+    fn visit_sum(&mut self, left: &Function, right: &Function) { self.visit_binary(left, right); }
+    fn visit_difference(&mut self, left: &Function, right: &Function) { self.visit_binary(left, right); }
+    fn visit_product(&mut self, left: &Function, right: &Function) { self.visit_binary(left, right); }
+    fn visit_quotient(&mut self, top: &Function, bottom: &Function) { self.visit_binary(top, bottom); }
+    fn visit_composed(&mut self, source: &Function, target: &Function) { self.visit_binary(source, target); }
+
+    fn visit_negative(&mut self, source: &Function) {
+
+        self.count += 1;
+        source.accept(self);
+    }
+
+    fn visit_unary(&mut self, _unary: &UnaryFunction) {
+
+        self.count += 1;
+    }
+
+    fn visit_variable(&mut self, _name: &str) {
+
+        self.count += 1;
+    }
+
+    fn visit_clamp(&mut self, source: &Function, _lo: f64, _hi: f64) {
+
+        self.count += 1;
+        source.accept(self);
+    }
+
+    fn visit_piecewise(&mut self, _threshold: f64, below: &Function, above: &Function) {
+
+        self.visit_binary(below, above);
+    }
+
+    fn visit_opaque(&mut self, _opaque: &OpaqueFunction) {
+
+        self.count += 1;
+    }
+
+    fn visit_power(&mut self, base: &Function, _power: i32) {
+
+        self.count += 1;
+        base.accept(self);
+    }
+}
+
+fn const_value(function: &dyn FunctionTrait) -> Option<f64> {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => Some(c),
+        _ => None,
+    }
+}
+
+fn simplify_node(function: Function) -> Function {
+
+    match Shape::of(&*function) {
+
+        Shape::Sum(left, right) => match (const_value(&*left), const_value(&*right)) {
+
+            (Some(l), Some(r)) => UnaryFunction::Const(l + r).new(),
+            (Some(0.0), _) => right,
+            (_, Some(0.0)) => left,
+            _ if left.structurally_equals(&*right) => UnaryFunction::Const(2.0).new().mul(left),
+            _ => function,
+        },
+
+        Shape::Difference(left, right) => match (const_value(&*left), const_value(&*right)) {
+
+            (Some(l), Some(r)) => UnaryFunction::Const(l - r).new(),
+            (Some(0.0), _) => right.neg(),
+            (_, Some(0.0)) => left,
+            _ if left.structurally_equals(&*right) => UnaryFunction::Const(0.0).new(),
+            _ => function,
+        },
+
+        Shape::Negative(source) => match (const_value(&*source), Shape::of(&*source)) {
+
+            (Some(c), _) => UnaryFunction::Const(-c).new(),
+            (_, Shape::Negative(inner)) => inner,
+            _ => function,
+        },
+
+        Shape::Product(left, right) => match (const_value(&*left), const_value(&*right)) {
+
+            (Some(l), Some(r)) => UnaryFunction::Const(l * r).new(),
+            (Some(0.0), _) | (_, Some(0.0)) => UnaryFunction::Const(0.0).new(),
+            (Some(1.0), _) => right,
+            (_, Some(1.0)) => left,
+            _ => function,
+        },
+
+        Shape::Quotient(top, bottom) => match (const_value(&*top), const_value(&*bottom)) {
+
+            (Some(t), Some(b)) => UnaryFunction::Const(t / b).new(),
+            (Some(0.0), _) => UnaryFunction::Const(0.0).new(),
+            (_, Some(1.0)) => top,
+            _ => function,
+        },
+
+        Shape::Composed(source, target) => match (Shape::of(&*source), Shape::of(&*target)) {
+
+            (Shape::Unary(UnaryFunction::Const(_)), _) => source,
+            (Shape::Unary(UnaryFunction::Id), _) => target,
+            (_, Shape::Unary(UnaryFunction::Id)) => source,
+            (Shape::Unary(unary), Shape::Unary(UnaryFunction::Const(c))) => UnaryFunction::Const(unary.eval(&c)).new(),
+            _ => function,
+        },
+
+        Shape::Clamp(source, lo, hi) => match const_value(&*source) {
+
+            Some(c) => UnaryFunction::Const(c.clamp(lo, hi)).new(),
+            None => function,
+        },
+
+        Shape::Power(base, power) => match (const_value(&*base), power) {
+
+            (Some(c), _) => UnaryFunction::Const(c.powi(power)).new(),
+            (None, 0) => UnaryFunction::Const(1.0).new(),
+            (None, 1) => base,
+            _ => function,
+        },
+
+        _ => function,
+    }
+}
+
+fn zero_small_constants(function: &dyn FunctionTrait, epsilon: f64) -> Function {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) if c.abs() < epsilon => UnaryFunction::Const(0.0).new(),
+        shape => shape.map(|child| zero_small_constants(&**child, epsilon)),
+    }
+}
+
+fn polynomial_of(function: &dyn FunctionTrait) -> Option<Vec<f64>> {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => Some(vec![c]),
+        Shape::Unary(UnaryFunction::Id) => Some(vec![0.0, 1.0]),
+        Shape::Variable(ref name) if name == "x" => Some(vec![0.0, 1.0]),
+        Shape::Sum(left, right) => Some(add_polynomials(&polynomial_of(&*left)?, &polynomial_of(&*right)?, 1.0)),
+        Shape::Difference(left, right) => Some(add_polynomials(&polynomial_of(&*left)?, &polynomial_of(&*right)?, -1.0)),
+        Shape::Negative(source) => Some(polynomial_of(&*source)?.iter().map(|c| -c).collect()),
+        Shape::Product(left, right) => Some(mul_polynomials(&polynomial_of(&*left)?, &polynomial_of(&*right)?)),
+
+        Shape::Quotient(top, bottom) => {
+
+            let top = polynomial_of(&*top)?;
+
+            match trim_polynomial(polynomial_of(&*bottom)?).as_slice() {
+
+                [c] => Some(top.iter().map(|t| t / c).collect()),
+                _ => None,
+            }
+        },
+
+        // Horner's method with polynomial arithmetic substitutes the target into the source
+        Shape::Composed(source, target) => {
+
+            let source = polynomial_of(&*source)?;
+            let target = polynomial_of(&*target)?;
+
+            Some(source.iter().rev().fold(vec![], |acc, c| add_polynomials(&mul_polynomials(&acc, &target), &[*c], 1.0)))
+        },
+
+        Shape::Power(base, power) if power >= 0 => {
+
+            let base = polynomial_of(&*base)?;
+
+            Some((0..power).fold(vec![1.0], |acc, _| mul_polynomials(&acc, &base)))
+        },
+
+        Shape::Polynomial(coeffs) => Some(coeffs),
+        Shape::Memo(memo) => polynomial_of(&*memo.inner),
+        Shape::Named(_, inner) => polynomial_of(&*inner),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => polynomial_of(&*inner),
+        _ => None,
+    }
+}
+
+// Like polynomial_of but in terms of whichever one non-arithmetic subtree the sums and
+// products bottom out in, which gets stored in inner
+fn polynomial_over(function: &dyn FunctionTrait, inner: &mut Option<Function>) -> Option<Vec<f64>> {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => Some(vec![c]),
+        Shape::Sum(left, right) => Some(add_polynomials(&polynomial_over(&*left, inner)?, &polynomial_over(&*right, inner)?, 1.0)),
+        Shape::Difference(left, right) => Some(add_polynomials(&polynomial_over(&*left, inner)?, &polynomial_over(&*right, inner)?, -1.0)),
+        Shape::Negative(source) => Some(polynomial_over(&*source, inner)?.iter().map(|c| -c).collect()),
+        Shape::Product(left, right) => Some(mul_polynomials(&polynomial_over(&*left, inner)?, &polynomial_over(&*right, inner)?)),
+
+        Shape::Quotient(top, bottom) => {
+
+            let top = polynomial_over(&*top, inner)?;
+            let c = const_value(&*bottom)?;
+
+            Some(top.iter().map(|t| t / c).collect())
+        },
+
+        Shape::Power(base, power) if power >= 0 => {
+
+            let base = polynomial_over(&*base, inner)?;
+
+            Some((0..power).fold(vec![1.0], |acc, _| mul_polynomials(&acc, &base)))
+        },
+
+        Shape::Polynomial(coeffs) => polynomial_over(&*polynomial_tree(&coeffs), inner),
+        Shape::Named(_, named) => polynomial_over(&*named, inner),
+
+        _ => match inner {
+
+            Some(known) if known.structurally_equals(function) => Some(vec![0.0, 1.0]),
+            Some(_) => None,
+
+            None => {
+
+                *inner = Some(Shape::of(function).map(Rc::clone));
+                Some(vec![0.0, 1.0])
+            },
+        },
+    }
+}
+
+fn horner(coeffs: &[f64], u: f64) -> f64 {
+
+    coeffs.iter().rev().fold(0.0, |acc, c| acc * u + c)
+}
+
+fn compile_binary(left: &dyn FunctionTrait, right: &dyn FunctionTrait, op: fn(f64, f64) -> f64) -> Box<dyn Fn(f64) -> f64> {
+
+    let (l, r) = (compile_fast(left), compile_fast(right));
+
+    Box::new(move |x| op(l(x), r(x)))
+}
+
+fn compile_fast(function: &dyn FunctionTrait) -> Box<dyn Fn(f64) -> f64> {
+
+    let mut inner = None;
+
+    // Only worth it once there's more than one power to go through
+    if let Some(coeffs) = polynomial_over(function, &mut inner).map(trim_polynomial) {
+
+        if coeffs.len() > 2 {
+
+            let u = match inner {
+
+                Some(inner) => compile_fast(&*inner),
+                None => return Box::new(move |_| horner(&coeffs, 0.0)),
+            };
+
+            return Box::new(move |x| horner(&coeffs, u(x)));
+        }
+    }
+
+    match Shape::of(function) {
+
+        Shape::Sum(left, right) => compile_binary(&*left, &*right, |l, r| l + r),
+        Shape::Difference(left, right) => compile_binary(&*left, &*right, |l, r| l - r),
+        Shape::Product(left, right) => compile_binary(&*left, &*right, |l, r| l * r),
+        Shape::Quotient(top, bottom) => compile_binary(&*top, &*bottom, |t, b| t / b),
+
+        Shape::Negative(source) => {
+
+            let s = compile_fast(&*source);
+            Box::new(move |x| -s(x))
+        },
+
+        Shape::Composed(source, target) => {
+
+            let (s, t) = (compile_fast(&*source), compile_fast(&*target));
+            Box::new(move |x| s(t(x)))
+        },
+
+        Shape::Power(base, power) => {
+
+            let b = compile_fast(&*base);
+            Box::new(move |x| b(x).powi(power))
+        },
+
+        Shape::Unary(unary) => Box::new(move |x| unary.eval(&x)),
+        shape => shape.map(Rc::clone).as_closure(),
+    }
+}
+
+fn trim_polynomial(mut coeffs: Vec<f64>) -> Vec<f64> {
+
+    while coeffs.last() == Some(&0.0) {
+
+        coeffs.pop();
+    }
+
+    coeffs
+}
+
+fn add_polynomials(left: &[f64], right: &[f64], sign: f64) -> Vec<f64> {
+
+    (0..left.len().max(right.len()))
+        .map(|i| left.get(i).unwrap_or(&0.0) + sign * right.get(i).unwrap_or(&0.0))
+        .collect()
+}
+
+fn mul_polynomials(left: &[f64], right: &[f64]) -> Vec<f64> {
+
+    if left.is_empty() || right.is_empty() {
+
+        return vec![];
+    }
+
+    let mut result = vec![0.0; left.len() + right.len() - 1];
+
+    for (i, l) in left.iter().enumerate() {
+
+        for (j, r) in right.iter().enumerate() {
+
+            result[i + j] += l * r;
+        }
+    }
+
+    result
+}
+
+fn monomial(coeff: f64, power: usize) -> Function {
+
+    let x_power = (1..power).fold(UnaryFunction::Id.new(), |acc, _| acc.mul(UnaryFunction::Id.new()));
+
+    match (coeff, power) {
+
+        (_, 0) => UnaryFunction::Const(coeff).new(),
+        (1.0, _) => x_power,
+        _ => UnaryFunction::Const(coeff).new().mul(x_power),
+    }
+}
+
+fn leading_monomial(coeffs: &[f64]) -> Function {
+
+    match coeffs.last() {
+
+        Some(c) => monomial(*c, coeffs.len() - 1),
+        None => UnaryFunction::Const(0.0).new(),
+    }
+}
+
+fn affine_of(function: &dyn FunctionTrait) -> Option<(f64, f64)> {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => Some((0.0, c)),
+        Shape::Unary(UnaryFunction::Id) => Some((1.0, 0.0)),
+        Shape::Variable(ref name) if name == "x" => Some((1.0, 0.0)),
+
+        Shape::Sum(left, right) => {
+
+            let (l_m, l_c) = affine_of(&*left)?;
+            let (r_m, r_c) = affine_of(&*right)?;
+
+            Some((l_m + r_m, l_c + r_c))
+        },
+
+        Shape::Difference(left, right) => {
+
+            let (l_m, l_c) = affine_of(&*left)?;
+            let (r_m, r_c) = affine_of(&*right)?;
+
+            Some((l_m - r_m, l_c - r_c))
+        },
+
+        Shape::Negative(source) => affine_of(&*source).map(|(m, c)| (-m, -c)),
+        Shape::Memo(memo) => affine_of(&*memo.inner),
+        Shape::Named(_, inner) => affine_of(&*inner),
+
+        Shape::Polynomial(coeffs) => match trim_polynomial(coeffs).as_slice() {
+
+            [] => Some((0.0, 0.0)),
+            [c] => Some((0.0, *c)),
+            [c, m] => Some((*m, *c)),
+            _ => None,
+        },
+
+        Shape::Product(left, right) => {
+
+            let (l_m, l_c) = affine_of(&*left)?;
+            let (r_m, r_c) = affine_of(&*right)?;
+
+            if l_m == 0.0 {
+
+                Some((l_c * r_m, l_c * r_c))
+            } else if r_m == 0.0 {
+
+                Some((r_c * l_m, r_c * l_c))
+            } else {
+
+                None
+            }
+        },
+
+        Shape::Quotient(top, bottom) => {
+
+            let (t_m, t_c) = affine_of(&*top)?;
+
+            match affine_of(&*bottom)? {
+
+                (b_m, b_c) if b_m == 0.0 && b_c != 0.0 => Some((t_m / b_c, t_c / b_c)),
+                _ => None,
+            }
+        },
+
+        Shape::Composed(source, target) => {
+
+            let (s_m, s_c) = affine_of(&*source)?;
+            let (t_m, t_c) = affine_of(&*target)?;
+
+            Some((s_m * t_m, s_m * t_c + s_c))
+        },
+
+        _ => None,
+    }
+}
+
+enum Periodicity {
+
+    Constant,
+    Periodic(f64),
+    Aperiodic,
+}
+
+fn period_of(function: &dyn FunctionTrait) -> Periodicity {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(_)) => Periodicity::Constant,
+        Shape::Unary(UnaryFunction::Sin) | Shape::Unary(UnaryFunction::Cos) => Periodicity::Periodic(2.0 * std::f64::consts::PI),
+        Shape::Unary(_) => Periodicity::Aperiodic,
+        Shape::Variable(ref name) if name == "x" => Periodicity::Aperiodic,
+        Shape::Variable(_) => Periodicity::Constant,
+
+        Shape::Sum(left, right) | Shape::Difference(left, right) | Shape::Product(left, right) | Shape::Quotient(left, right) => {
+
+            combine_periods(period_of(&*left), period_of(&*right))
+        },
+
+        Shape::Negative(source) | Shape::Clamp(source, _, _) | Shape::Power(source, _) => period_of(&*source),
+        Shape::Polynomial(coeffs) => period_of(&*polynomial_tree(&coeffs)),
+        Shape::Memo(memo) => period_of(&*memo.inner),
+        Shape::Named(_, inner) => period_of(&*inner),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => period_of(&*inner),
+
+        Shape::Composed(source, target) => {
+
+            match (period_of(&*source), period_of(&*target)) {
+
+                (Periodicity::Constant, _) | (_, Periodicity::Constant) => Periodicity::Constant,
+                (_, Periodicity::Periodic(period)) => Periodicity::Periodic(period),
+
+                (Periodicity::Periodic(period), Periodicity::Aperiodic) => {
+
+                    match affine_of(&*target) {
+
+                        Some((m, _)) if m != 0.0 => Periodicity::Periodic(period / m.abs()),
+                        _ => Periodicity::Aperiodic,
+                    }
+                },
+
+                _ => Periodicity::Aperiodic,
+            }
+        },
+
+        Shape::Piecewise(_, _, _) | Shape::Opaque(_) => Periodicity::Aperiodic,
+    }
+}
+
+fn combine_periods(left: Periodicity, right: Periodicity) -> Periodicity {
+
+    match (left, right) {
+
+        (Periodicity::Constant, other) | (other, Periodicity::Constant) => other,
+
+        (Periodicity::Periodic(l_period), Periodicity::Periodic(r_period)) => {
+
+            // The common period is the lcm, which only exists if the ratio is rational
+            let ratio = l_period / r_period;
+
+            for denominator in 1..=PERIOD_MAX_DENOMINATOR {
+
+                let numerator = (ratio * denominator as f64).round();
+
+                if numerator >= 1.0 && (ratio * denominator as f64 - numerator).abs() < 1e-9 {
+
+                    return Periodicity::Periodic(l_period * denominator as f64);
+                }
+            }
+
+            Periodicity::Aperiodic
+        },
+
+        _ => Periodicity::Aperiodic,
+    }
+}
+
+const PERIOD_MAX_DENOMINATOR: usize = 64;
+
+pub fn differentiate(f: &Function) -> Function {
+
+    f.diff()
+}
+
+pub fn evaluate(f: &Function, x: f64) -> f64 {
+
+    f.eval(&x)
+}
+
+// Both partials are simplified first, so the quotient comes out as -(2x) / (2y) for a
+// circle rather than the raw sums of products the product rule leaves behind
+pub fn implicit_dy_dx(f: &Function) -> Function {
+
+    f.partial_diff("x").simplify_fully().neg().div(f.partial_diff("y").simplify_fully())
+}
+
+// f g' - g f', identically zero for linearly dependent solutions of a linear ODE
+pub fn wronskian(f: &Function, g: &Function) -> Function {
+
+    Rc::clone(f).mul(g.diff()).sub(Rc::clone(g).mul(f.diff()))
+}
+
+// The series sum of coeffs[k] * (x - center)^k, the inverse of taylor_coefficients
+pub fn from_taylor(coeffs: &[f64], center: f64) -> Function {
+
+    let polynomial = PolynomialFunction::new(coeffs.to_vec());
+
+    if center == 0.0 {
+
+        return polynomial;
+    }
+
+    polynomial.of(UnaryFunction::Id.new().sub(UnaryFunction::Const(center).new()))
+}
+
+// The Lagrange polynomial through points, whose x values must be distinct
+pub fn interpolate(points: &[(f64, f64)]) -> Function {
+
+    let mut coeffs = Vec::new();
+
+    for (i, (x_i, y_i)) in points.iter().enumerate() {
+
+        let mut basis = vec![*y_i];
+
+        for (j, (x_j, _)) in points.iter().enumerate() {
+
+            if i != j {
+
+                basis = mul_polynomials(&basis, &[-x_j / (x_i - x_j), 1.0 / (x_i - x_j)]);
+            }
+        }
+
+        coeffs = add_polynomials(&coeffs, &basis, 1.0);
+    }
+
+    PolynomialFunction::new(trim_polynomial(coeffs))
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ParseError {
+
+    UnexpectedChar(char),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    UnknownFunction(String),
+    InvalidNumber(String),
+    InvalidUtf8,
+    Io(std::io::ErrorKind),
+}
+
+impl fmt::Display for ParseError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        match self {
+
+            ParseError::UnexpectedChar(c) => write!(f, "Unexpected character '{}'", c),
+            ParseError::UnexpectedToken(token) => write!(f, "Unexpected {}", token),
+            ParseError::UnexpectedEnd => write!(f, "Unexpected end of input"),
+            ParseError::UnknownFunction(name) => write!(f, "Unknown function {}", name),
+            ParseError::InvalidNumber(text) => write!(f, "Invalid number {}", text),
+            ParseError::InvalidUtf8 => write!(f, "Input is not valid UTF-8"),
+            ParseError::Io(kind) => write!(f, "Failed to read input: {:?}", kind),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Reads the infix form functions display as, with either x or $ as the argument, so the
+// output of to_string parses back for everything but clamps, piecewise and opaque functions
+pub fn parse(input: &str) -> Result<Function, ParseError> {
+
+    Parser::new(input.chars().map(Ok))?.parse()
+}
+
+// The reader is only pulled from as the parser needs the next character
+pub fn parse_reader<R: std::io::Read>(reader: R) -> Result<Function, ParseError> {
+
+    Parser::new(Utf8Chars { bytes: std::io::BufReader::new(reader).bytes() })?.parse()
+}
+
+struct Utf8Chars<R: std::io::Read> {
+
+    bytes: std::io::Bytes<std::io::BufReader<R>>,
+}
+
+impl<R: std::io::Read> Iterator for Utf8Chars<R> {
+
+    type Item = Result<char, ParseError>;
+
+    fn next(&mut self) -> Option<Result<char, ParseError>> {
+
+        let mut next_byte = || self.bytes.next().map(|byte| byte.map_err(|err| ParseError::Io(err.kind())));
+
+        let lead = match next_byte()? {
+
+            Ok(lead) => lead,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let width = match lead.leading_ones() {
+
+            0 => 1,
+            n @ 2..=4 => n as usize,
+            _ => return Some(Err(ParseError::InvalidUtf8)),
+        };
+
+        let mut buffer = [lead, 0, 0, 0];
+
+        for slot in buffer.iter_mut().take(width).skip(1) {
+
+            *slot = match next_byte() {
+
+                Some(Ok(byte)) => byte,
+                Some(Err(err)) => return Some(Err(err)),
+                None => return Some(Err(ParseError::InvalidUtf8)),
+            };
+        }
+
+        Some(std::str::from_utf8(&buffer[..width]).ok().and_then(|s| s.chars().next()).ok_or(ParseError::InvalidUtf8))
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Lexeme {
+
+    Number(f64),
+    Name(String),
+    Arg,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    Open,
+    Close,
+    End,
+}
+
+struct Parser<I: Iterator<Item = Result<char, ParseError>>> {
+
+    chars: std::iter::Peekable<I>,
+    current: Lexeme,
+}
+
+impl<I: Iterator<Item = Result<char, ParseError>>> Parser<I> {
+
+    fn new(chars: I) -> Result<Self, ParseError> {
+
+        let mut parser = Parser { chars: chars.peekable(), current: Lexeme::End };
+        parser.advance()?;
+
+        Ok(parser)
+    }
+
+    fn peek_char(&mut self) -> Result<Option<char>, ParseError> {
+
+        match self.chars.peek() {
+
+            Some(Ok(c)) => Ok(Some(*c)),
+            Some(Err(err)) => Err(err.clone()),
+            None => Ok(None),
+        }
+    }
+
+    fn take_while<P: Fn(char) -> bool>(&mut self, first: char, predicate: P) -> Result<String, ParseError> {
+
+        let mut text = first.to_string();
+
+        while let Some(c) = self.peek_char()? {
+
+            if !predicate(c) {
+
+                break;
+            }
+
+            text.push(c);
+            self.chars.next();
+        }
+
+        Ok(text)
+    }
+
+    fn advance(&mut self) -> Result<(), ParseError> {
+
+        let first = loop {
+
+            match self.chars.next().transpose()? {
+
+                Some(c) if c.is_whitespace() => continue,
+                Some(c) => break c,
+
+                None => {
+
+                    self.current = Lexeme::End;
+                    return Ok(());
+                },
+            }
+        };
+
+        self.current = match first {
+
+            '+' => Lexeme::Plus,
+            '-' => Lexeme::Minus,
+            '*' => Lexeme::Star,
+            '/' => Lexeme::Slash,
+            '^' => Lexeme::Caret,
+            '(' => Lexeme::Open,
+            ')' => Lexeme::Close,
+            '$' => Lexeme::Arg,
+
+            c if c.is_ascii_digit() || c == '.' => {
+
+                let text = self.take_while(c, |c| c.is_ascii_digit() || c == '.')?;
+
+                Lexeme::Number(text.parse().map_err(|_| ParseError::InvalidNumber(text))?)
+            },
+
+            c if c.is_alphabetic() => {
+
+                let text = self.take_while(c, |c| c.is_alphanumeric() || c == '_')?;
+
+                if text == "x" { Lexeme::Arg } else { Lexeme::Name(text) }
+            },
+
+            c => return Err(ParseError::UnexpectedChar(c)),
+        };
+
+        Ok(())
+    }
+
+    fn expect(&mut self, expected: Lexeme) -> Result<(), ParseError> {
+
+        if self.current == expected { self.advance() } else { Err(self.unexpected()) }
+    }
+
+    fn unexpected(&self) -> ParseError {
+
+        match &self.current {
+
+            Lexeme::End => ParseError::UnexpectedEnd,
+            lexeme => ParseError::UnexpectedToken(format!("{:?}", lexeme)),
+        }
+    }
+
+    fn parse(mut self) -> Result<Function, ParseError> {
+
+        let function = self.parse_sum()?;
+
+        if self.current == Lexeme::End { Ok(function) } else { Err(self.unexpected()) }
+    }
+
+    fn parse_sum(&mut self) -> Result<Function, ParseError> {
+
+        let mut function = self.parse_product()?;
+
+        loop {
+
+            function = match self.current {
+
+                Lexeme::Plus => { self.advance()?; function.add(self.parse_product()?) },
+                Lexeme::Minus => { self.advance()?; function.sub(self.parse_product()?) },
+                _ => return Ok(function),
+            };
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Function, ParseError> {
+
+        let mut function = self.parse_unary()?;
+
+        loop {
+
+            function = match self.current {
+
+                Lexeme::Star => { self.advance()?; function.mul(self.parse_unary()?) },
+                Lexeme::Slash => { self.advance()?; function.div(self.parse_unary()?) },
+                _ => return Ok(function),
+            };
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Function, ParseError> {
+
+        if self.current != Lexeme::Minus {
+
+            return self.parse_power();
+        }
+
+        self.advance()?;
+        let source = self.parse_unary()?;
+
+        match const_value(&*source) {
+
+            Some(c) => Ok(UnaryFunction::Const(-c).new()),
+            None => Ok(source.neg()),
+        }
+    }
+
+    // Only integer exponents are supported, as IntPowFunction is the only power there is
+    fn parse_power(&mut self) -> Result<Function, ParseError> {
+
+        let base = self.parse_atom()?;
+
+        if self.current != Lexeme::Caret {
+
+            return Ok(base);
+        }
+
+        self.advance()?;
+        let negative = self.current == Lexeme::Minus;
+
+        if negative {
+
+            self.advance()?;
+        }
+
+        match self.current {
+
+            Lexeme::Number(n) if n.fract() == 0.0 && n <= i32::MAX as f64 => {
+
+                self.advance()?;
+                let power = n as i32;
+
+                Ok(IntPowFunction::new(base, if negative { -power } else { power }))
+            },
+
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    // Coefficients are written straight against what they multiply, as in 2$ or 3sin($)
+    fn parse_atom(&mut self) -> Result<Function, ParseError> {
+
+        match std::mem::replace(&mut self.current, Lexeme::End) {
+
+            Lexeme::Number(c) => {
+
+                self.advance()?;
+
+                match self.current {
+
+                    Lexeme::Name(_) | Lexeme::Arg | Lexeme::Open => Ok(UnaryFunction::Const(c).new().mul(self.parse_power()?)),
+                    _ => Ok(UnaryFunction::Const(c).new()),
+                }
+            },
+
+            Lexeme::Arg => {
+
+                self.advance()?;
+                Ok(UnaryFunction::Id.new())
+            },
+
+            Lexeme::Open => {
+
+                self.advance()?;
+                let function = self.parse_sum()?;
+                self.expect(Lexeme::Close)?;
+
+                Ok(function)
+            },
+
+            Lexeme::Name(name) => {
+
+                self.advance()?;
+
+                if self.current != Lexeme::Open {
+
+                    return Ok(VariableFunction::new(&name));
+                }
+
+                let unary = match name.as_str() {
+
+                    "sin" => UnaryFunction::Sin,
+                    "cos" => UnaryFunction::Cos,
+                    "exp" => UnaryFunction::Exp,
+                    "ln" | "log" => UnaryFunction::Log,
+                    "Γ" | "gamma" => UnaryFunction::Gamma,
+                    "ψ" | "digamma" => UnaryFunction::Digamma,
+                    "erf" => UnaryFunction::Erf,
+                    "erfc" => UnaryFunction::Erfc,
+                    _ => return Err(ParseError::UnknownFunction(name)),
+                };
+
+                self.advance()?;
+                let argument = self.parse_sum()?;
+                self.expect(Lexeme::Close)?;
+
+                match Shape::of(&*argument) {
+
+                    Shape::Unary(UnaryFunction::Id) => Ok(unary.new()),
+                    _ => Ok(unary.new().of(argument)),
+                }
+            },
+
+            lexeme => {
+
+                self.current = lexeme;
+                Err(self.unexpected())
+            },
+        }
+    }
+}
+
+// Newton's method on the whole system, with guess giving the starting value of each of
+// funcs.variables() in order; None if it doesn't converge or the Jacobian goes singular
+pub fn solve_system(funcs: &VectorFunction, guess: &[f64], max_iter: usize, tol: f64) -> Option<Vec<f64>> {
+
+    let names = funcs.variables();
+    assert_eq!(names.len(), guess.len(), "Need one guess per variable!");
+
+    let vars: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+    let jacobian = funcs.jacobian(&vars);
+    let mut point = guess.to_vec();
+
+    for _ in 0..max_iter {
+
+        let env: HashMap<String, f64> = names.iter().cloned().zip(point.iter().cloned()).collect();
+        let residual: Vec<f64> = funcs.components().iter().map(|component| component.eval_env(&env)).collect();
+
+        if residual.iter().all(|r| r.abs() < tol) {
+
+            return Some(point);
+        }
+
+        let matrix = jacobian.iter().map(|row| row.iter().map(|entry| entry.eval_env(&env)).collect()).collect();
+        let step = solve_linear(matrix, residual.iter().map(|r| -r).collect())?;
+
+        for (value, delta) in point.iter_mut().zip(step) {
+
+            *value += delta;
+        }
+    }
+
+    None
+}
+
+// Gaussian elimination with partial pivoting
+fn solve_linear(mut matrix: Vec<Vec<f64>>, mut rhs: Vec<f64>) -> Option<Vec<f64>> {
+
+    let n = rhs.len();
+
+    if matrix.len() != n || matrix.iter().any(|row| row.len() != n) {
+
+        return None;
+    }
+
+    // A NaN or infinite entry has no meaningful solution, and would break the pivot ordering
+    if matrix.iter().flatten().chain(&rhs).any(|entry| !entry.is_finite()) {
+
+        return None;
+    }
+
+    for col in 0..n {
+
+        let pivot = (col..n).max_by(|&i, &j| matrix[i][col].abs().total_cmp(&matrix[j][col].abs()))?;
+
+        if matrix[pivot][col].abs() < f64::EPSILON {
+
+            return None;
+        }
+
+        matrix.swap(col, pivot);
+        rhs.swap(col, pivot);
+
+        let pivot_row = matrix[col].clone();
+
+        for row in col + 1..n {
+
+            let factor = matrix[row][col] / pivot_row[col];
+
+            for (entry, pivot_entry) in matrix[row].iter_mut().zip(&pivot_row).skip(col) {
+
+                *entry -= factor * pivot_entry;
+            }
+
+            rhs[row] -= factor * rhs[col];
+        }
+    }
+
+    let mut solution = vec![0.0; n];
+
+    for row in (0..n).rev() {
+
+        let known: f64 = (row + 1..n).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (rhs[row] - known) / matrix[row][row];
+    }
+
+    Some(solution)
+}
+
+pub enum Pattern {
+
+    Wild(String),
+    Const(f64),
+    Unary(UnaryFunction),
+    Variable(String),
+    Sum(Box<Pattern>, Box<Pattern>),
+    Difference(Box<Pattern>, Box<Pattern>),
+    Negative(Box<Pattern>),
+    Product(Box<Pattern>, Box<Pattern>),
+    Quotient(Box<Pattern>, Box<Pattern>),
+    Composed(Box<Pattern>, Box<Pattern>),
+}
+
+pub type Template = Pattern;
+
+impl Pattern {
+
+    pub fn wild(name: &str) -> Pattern {
+
+        Pattern::Wild(String::from(name))
+    }
+
+    // None if the template uses a wildcard the bindings don't have
+    pub fn instantiate(&self, bindings: &HashMap<String, Function>) -> Option<Function> {
+
+        Some(match self {
+
+            Pattern::Wild(name) => Rc::clone(bindings.get(name)?),
+            Pattern::Const(c) => UnaryFunction::Const(*c).new(),
+            Pattern::Unary(unary) => unary.new(),
+            Pattern::Variable(name) => VariableFunction::new(name),
+            Pattern::Sum(left, right) => left.instantiate(bindings)?.add(right.instantiate(bindings)?),
+            Pattern::Difference(left, right) => left.instantiate(bindings)?.sub(right.instantiate(bindings)?),
+            Pattern::Negative(source) => source.instantiate(bindings)?.neg(),
+            Pattern::Product(left, right) => left.instantiate(bindings)?.mul(right.instantiate(bindings)?),
+            Pattern::Quotient(top, bottom) => top.instantiate(bindings)?.div(bottom.instantiate(bindings)?),
+            Pattern::Composed(source, target) => source.instantiate(bindings)?.of(target.instantiate(bindings)?),
+        })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct OpCounts {
+
+    pub additions: usize,
+    pub multiplications: usize,
+    pub divisions: usize,
+    pub transcendentals: usize,
+}
+
+pub struct Rule {
+
+    pub lhs: Pattern,
+    pub rhs: Template,
+}
+
+fn match_into(function: &Function, pattern: &Pattern, bindings: &mut HashMap<String, Function>) -> bool {
+
+    match (pattern, Shape::of(&**function)) {
+
+        (Pattern::Wild(name), _) => match bindings.get(name) {
+
+            // A wildcard used twice has to bind the same subtree both times
+            Some(bound) => bound.structurally_equals(&**function),
+            None => {
+
+                bindings.insert(name.clone(), Rc::clone(function));
+                true
+            },
+        },
+
+        (Pattern::Const(c), Shape::Unary(UnaryFunction::Const(value))) => *c == value,
+        (Pattern::Unary(unary), Shape::Unary(value)) => *unary == value,
+        (Pattern::Variable(name), Shape::Variable(value)) => *name == value,
+        (Pattern::Negative(source), Shape::Negative(f_source)) => match_into(&f_source, source, bindings),
+
+        (Pattern::Sum(left, right), Shape::Sum(f_left, f_right))
+        | (Pattern::Difference(left, right), Shape::Difference(f_left, f_right))
+        | (Pattern::Product(left, right), Shape::Product(f_left, f_right))
+        | (Pattern::Quotient(left, right), Shape::Quotient(f_left, f_right))
+        | (Pattern::Composed(left, right), Shape::Composed(f_left, f_right)) => {
+
+            match_into(&f_left, left, bindings) && match_into(&f_right, right, bindings)
+        },
+
+        _ => false,
+    }
+}
+
+// Each node lives in its own Rc allocation, which also holds the two reference counts
+fn footprint_of(function: &dyn FunctionTrait, seen: &mut HashSet<*const ()>) -> usize {
+
+    let mut total = std::mem::size_of_val(function) + 2 * std::mem::size_of::<usize>();
+
+    for child in Shape::of(function).children() {
+
+        if seen.insert(Rc::as_ptr(child) as *const ()) {
+
+            total += footprint_of(&**child, seen);
+        }
+    }
+
+    total
+}
+
+fn replace_ptr_below(function: &dyn FunctionTrait, target: &Function, replacement: &Function) -> Option<Function> {
+
+    let shape = Shape::of(function);
+
+    let replaced: Vec<Option<Function>> = shape.children().into_iter().map(|child| {
+
+        if Rc::ptr_eq(child, target) { Some(Rc::clone(replacement)) } else { replace_ptr_below(&**child, target, replacement) }
+
+    }).collect();
+
+    if replaced.iter().all(Option::is_none) {
+
+        return None;
+    }
+
+    let mut replaced = replaced.into_iter();
+
+    Some(shape.map(|child| replaced.next().flatten().unwrap_or_else(|| Rc::clone(child))))
+}
+
+// Collects the non-constant factors of a product/quotient/power tree along with the power
+// each ends up raised to, repeated factors sharing one entry; constants and negations are
+// multiplied into scale, so the function is scale times the product of the factors
+fn log_factors(function: Function, exponent: i32, factors: &mut Vec<(Function, i32)>, scale: &mut f64) {
+
+    match Shape::of(&*function) {
+
+        Shape::Product(left, right) => {
+
+            log_factors(left, exponent, factors, scale);
+            log_factors(right, exponent, factors, scale);
+        },
+
+        Shape::Quotient(top, bottom) => {
+
+            log_factors(top, exponent, factors, scale);
+            log_factors(bottom, -exponent, factors, scale);
+        },
+
+        Shape::Power(base, power) => log_factors(base, exponent * power, factors, scale),
+
+        Shape::Negative(source) => {
+
+            *scale = -*scale;
+            log_factors(source, exponent, factors, scale);
+        },
+
+        Shape::Unary(UnaryFunction::Const(c)) => *scale *= c.powi(exponent),
+
+        _ => match factors.iter_mut().find(|(known, _)| known.structurally_equals(&*function)) {
+
+            Some((_, known_exponent)) => *known_exponent += exponent,
+            None => factors.push((function, exponent)),
+        },
+    }
+}
+
+fn share_squares(function: &dyn FunctionTrait) -> Function {
+
+    match Shape::of(function) {
+
+        Shape::Product(left, right) if Rc::ptr_eq(&left, &right) => IntPowFunction::new(share_squares(&*left), 2),
+        shape => shape.map(|child| share_squares(&**child)),
+    }
+}
+
+// Splits a function into numerator and denominator, None standing for a denominator of 1
+// so that fractions don't pick up factors of 1 as they're merged
+fn fraction_of(function: &dyn FunctionTrait) -> (Function, Option<Function>) {
+
+    let times = |l: Function, r: Option<Function>| match r { Some(r) => l.mul(r), None => l };
+
+    let times_both = |l: Option<Function>, r: Option<Function>| match (l, r) {
+
+        (Some(l), r) => Some(times(l, r)),
+        (None, r) => r,
+    };
+
+    match Shape::of(function) {
+
+        Shape::Sum(left, right) | Shape::Difference(left, right) => {
+
+            let subtract = matches!(Shape::of(function), Shape::Difference(_, _));
+            let join = |l: Function, r: Function| if subtract { l.sub(r) } else { l.add(r) };
+
+            let ((l_top, l_bottom), (r_top, r_bottom)) = (fraction_of(&*left), fraction_of(&*right));
+
+            match (l_bottom, r_bottom) {
+
+                (Some(l), Some(r)) if l.structurally_equals(&*r) => (join(l_top, r_top), Some(l)),
+                (l, r) => (join(times(l_top, r.clone()), times(r_top, l.clone())), times_both(l, r)),
+            }
+        },
+
+        Shape::Negative(source) => {
+
+            let (top, bottom) = fraction_of(&*source);
+
+            (top.neg(), bottom)
+        },
+
+        Shape::Product(left, right) => {
+
+            let ((l_top, l_bottom), (r_top, r_bottom)) = (fraction_of(&*left), fraction_of(&*right));
+
+            (l_top.mul(r_top), times_both(l_bottom, r_bottom))
+        },
+
+        Shape::Quotient(top, bottom) => {
+
+            let ((t_top, t_bottom), (b_top, b_bottom)) = (fraction_of(&*top), fraction_of(&*bottom));
+
+            (times(t_top, b_bottom), Some(times(b_top, t_bottom)))
+        },
+
+        Shape::Power(base, power) => {
+
+            let (top, bottom) = fraction_of(&*base);
+            let raise = |f: Function| IntPowFunction::new(f, power.abs());
+
+            match (power < 0, bottom) {
+
+                (false, bottom) => (raise(top), bottom.map(raise)),
+                (true, Some(bottom)) => (raise(bottom), Some(raise(top))),
+                (true, None) => (UnaryFunction::Const(1.0).new(), Some(raise(top))),
+            }
+        },
+
+        shape => (shape.map(Rc::clone), None),
+    }
+}
+
+// The negation of an already simplified function, without leaving a double negative or
+// wrapping a sum or difference
+fn negated(function: Function) -> Function {
+
+    match Shape::of(&*function) {
+
+        Shape::Negative(source) => source,
+        Shape::Difference(left, right) => right.sub(left),
+        Shape::Sum(left, right) => negated(left).sub(right),
+        Shape::Unary(UnaryFunction::Const(c)) => UnaryFunction::Const(-c).new(),
+        _ => function.neg(),
+    }
+}
+
+// Splits u(g) * u(g) into u and g for u either sin or cos
+fn trig_square(function: &dyn FunctionTrait) -> Option<(UnaryFunction, Function)> {
+
+    let trig_of = |factor: &Function| match Shape::of(&**factor) {
+
+        Shape::Unary(kind @ (UnaryFunction::Sin | UnaryFunction::Cos)) => Some((kind, UnaryFunction::Id.new())),
+
+        Shape::Composed(source, target) => match Shape::of(&*source) {
+
+            Shape::Unary(kind @ (UnaryFunction::Sin | UnaryFunction::Cos)) => Some((kind, target)),
+            _ => None,
+        },
+
+        _ => None,
+    };
+
+    match Shape::of(function) {
+
+        Shape::Product(left, right) => {
+
+            let (l_kind, l_arg) = trig_of(&left)?;
+            let (r_kind, r_arg) = trig_of(&right)?;
+
+            if l_kind == r_kind && l_arg.structurally_equals(&*r_arg) { Some((l_kind, l_arg)) } else { None }
+        },
+
+        _ => None,
+    }
+}
+
+fn central_difference<F: Fn(f64) -> f64>(f: F, x: f64) -> f64 {
+
+    let h = f64::EPSILON.cbrt() * x.abs().max(1.0);
+
+    (f(x + h) - f(x - h)) / (2.0 * h)
+}
+
+#[cfg(feature = "rand")]
+pub fn random_function<R: Rng>(depth: usize, rng: &mut R) -> Function {
+
+    const LEAVES: [UnaryFunction; 6] = [UnaryFunction::Id, UnaryFunction::Sin, UnaryFunction::Cos, UnaryFunction::Exp, UnaryFunction::Log, UnaryFunction::Erf];
+
+    // Stop early sometimes so the trees don't all have exactly the maximum depth
+    if depth == 0 || rng.gen_bool(0.25) {
+
+        return match rng.gen_range(0..=LEAVES.len()) {
+
+            0 => UnaryFunction::Const(rng.gen_range(-2.0..2.0)).new(),
+            i => LEAVES[i - 1].new(),
+        };
+    }
+
+    match rng.gen_range(0..6) {
+
+        0 => random_function(depth - 1, rng).add(random_function(depth - 1, rng)),
+        1 => random_function(depth - 1, rng).sub(random_function(depth - 1, rng)),
+        2 => random_function(depth - 1, rng).neg(),
+        3 => random_function(depth - 1, rng).mul(random_function(depth - 1, rng)),
+        4 => random_function(depth - 1, rng).div(random_function(depth - 1, rng)),
+        _ => random_function(depth - 1, rng).of(random_function(depth - 1, rng)),
+    }
+}
+
+fn half_ulp(value: f64) -> f64 {
+
+    0.5 * f64::EPSILON * value.abs()
+}
+
+fn eval_with_error(function: &dyn FunctionTrait, x: f64, x_err: f64) -> (f64, f64) {
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => (c, 0.0),
+        Shape::Unary(UnaryFunction::Id) => (x, x_err),
+        Shape::Variable(ref name) if name == "x" => (x, x_err),
+
+        Shape::Unary(unary) => {
+
+            let value = unary.eval(&x);
+
+            (value, unary.diff().eval(&x).abs() * x_err + half_ulp(value))
+        },
+
+        Shape::Opaque(opaque) => {
+
+            let value = opaque.eval(&x);
+
+            (value, opaque.diff().eval(&x).abs() * x_err + half_ulp(value))
+        },
+
+        Shape::Sum(left, right) => {
+
+            let (l_value, l_err) = eval_with_error(&*left, x, x_err);
+            let (r_value, r_err) = eval_with_error(&*right, x, x_err);
+            let value = l_value + r_value;
+
+            (value, l_err + r_err + half_ulp(value))
+        },
+
+        Shape::Difference(left, right) => {
+
+            let (l_value, l_err) = eval_with_error(&*left, x, x_err);
+            let (r_value, r_err) = eval_with_error(&*right, x, x_err);
+            let value = l_value - r_value;
+
+            (value, l_err + r_err + half_ulp(value))
+        },
+
+        Shape::Negative(source) => {
+
+            let (value, err) = eval_with_error(&*source, x, x_err);
+
+            (-value, err)
+        },
+
+        Shape::Product(left, right) => {
+
+            let (l_value, l_err) = eval_with_error(&*left, x, x_err);
+            let (r_value, r_err) = eval_with_error(&*right, x, x_err);
+            let value = l_value * r_value;
+
+            (value, r_value.abs() * l_err + l_value.abs() * r_err + l_err * r_err + half_ulp(value))
+        },
+
+        Shape::Quotient(top, bottom) => {
+
+            let (t_value, t_err) = eval_with_error(&*top, x, x_err);
+            let (b_value, b_err) = eval_with_error(&*bottom, x, x_err);
+            let value = t_value / b_value;
+
+            // Once the denominator's error reaches its size it could be zero, so nothing is bounded
+            if b_err >= b_value.abs() || !value.is_finite() {
+
+                return (value, f64::INFINITY);
+            }
+
+            (value, (t_err + value.abs() * b_err) / (b_value.abs() - b_err) + half_ulp(value))
+        },
+
+        Shape::Composed(source, target) => {
+
+            let (inner, inner_err) = eval_with_error(&*target, x, x_err);
+
+            eval_with_error(&*source, inner, inner_err)
+        },
+
+        Shape::Clamp(source, lo, hi) => {
+
+            let (value, err) = eval_with_error(&*source, x, x_err);
+
+            (value.clamp(lo, hi), err)
+        },
+
+        Shape::Piecewise(threshold, below, above) => {
+
+            if x < threshold { eval_with_error(&*below, x, x_err) } else { eval_with_error(&*above, x, x_err) }
+        },
+
+        Shape::Power(base, power) => {
+
+            let (b_value, b_err) = eval_with_error(&*base, x, x_err);
+            let value = b_value.powi(power);
+
+            // x^0 is exactly 1 whatever the base, and b^(-1) would be infinite at 0
+            if power == 0 {
+
+                return (value, half_ulp(value));
+            }
+
+            (value, (power as f64 * b_value.powi(power - 1)).abs() * b_err + half_ulp(value))
+        },
+
+        Shape::Polynomial(coeffs) => eval_with_error(&*polynomial_tree(&coeffs), x, x_err),
+
+        // The bound needs every intermediate value, which the cache doesn't keep
+        Shape::Memo(memo) => eval_with_error(&*memo.inner, x, x_err),
+        Shape::Named(_, inner) => eval_with_error(&*inner, x, x_err),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => eval_with_error(&*inner, x, x_err),
+
+        Shape::Variable(name) => panic!("Cannot evaluate free variable {} without an environment!", name),
+    }
+}
+
+// Pushes the jet (g, g', g'') of an inner function through a leaf u with known derivatives
+fn chain_jet(value: f64, first: f64, second: f64, input: (f64, f64, f64)) -> (f64, f64, f64) {
+
+    let (_, x1, x2) = input;
+
+    (value, first * x1, second * x1 * x1 + first * x2)
+}
+
+fn eval_jet(function: &dyn FunctionTrait, input: (f64, f64, f64)) -> (f64, f64, f64) {
+
+    let x = input.0;
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => (c, 0.0, 0.0),
+        Shape::Unary(UnaryFunction::Id) => input,
+        Shape::Variable(ref name) if name == "x" => input,
+
+        Shape::Unary(unary) => {
+
+            let first = unary.diff();
+
+            chain_jet(unary.eval(&x), first.eval(&x), first.diff().eval(&x), input)
+        },
+
+        Shape::Opaque(opaque) => {
+
+            let first = opaque.diff();
+
+            chain_jet(opaque.eval(&x), first.eval(&x), first.diff().eval(&x), input)
+        },
+
+        Shape::Sum(left, right) => {
+
+            let (l0, l1, l2) = eval_jet(&*left, input);
+            let (r0, r1, r2) = eval_jet(&*right, input);
+
+            (l0 + r0, l1 + r1, l2 + r2)
+        },
+
+        Shape::Difference(left, right) => {
+
+            let (l0, l1, l2) = eval_jet(&*left, input);
+            let (r0, r1, r2) = eval_jet(&*right, input);
+
+            (l0 - r0, l1 - r1, l2 - r2)
+        },
+
+        Shape::Negative(source) => {
+
+            let (s0, s1, s2) = eval_jet(&*source, input);
+
+            (-s0, -s1, -s2)
+        },
+
+        Shape::Product(left, right) => {
+
+            let (l0, l1, l2) = eval_jet(&*left, input);
+            let (r0, r1, r2) = eval_jet(&*right, input);
+
+            (l0 * r0, l1 * r0 + l0 * r1, l2 * r0 + 2.0 * l1 * r1 + l0 * r2)
+        },
+
+        Shape::Quotient(top, bottom) => {
+
+            let (t0, t1, t2) = eval_jet(&*top, input);
+            let (b0, b1, b2) = eval_jet(&*bottom, input);
+
+            let q0 = t0 / b0;
+            let q1 = (t1 - q0 * b1) / b0;
+            let q2 = (t2 - 2.0 * q1 * b1 - q0 * b2) / b0;
+
+            (q0, q1, q2)
+        },
+
+        Shape::Composed(source, target) => {
+
+            let inner = eval_jet(&*target, input);
+            let (s0, s1, s2) = eval_jet(&*source, (inner.0, 1.0, 0.0));
+
+            chain_jet(s0, s1, s2, inner)
+        },
+
+        Shape::Clamp(source, lo, hi) => {
+
+            let (s0, s1, s2) = eval_jet(&*source, input);
+
+            if s0 < lo || s0 > hi { (s0.clamp(lo, hi), 0.0, 0.0) } else { (s0, s1, s2) }
+        },
+
+        Shape::Piecewise(threshold, below, above) => {
+
+            if x < threshold { eval_jet(&*below, input) } else { eval_jet(&*above, input) }
+        },
+
+        Shape::Power(base, power) => {
+
+            let inner = eval_jet(&*base, input);
+            let (n, b) = (power as f64, inner.0);
+
+            // A zero coefficient is skipped rather than multiplied into b^(negative), which is infinite at 0
+            let first = if power == 0 { 0.0 } else { n * b.powi(power - 1) };
+            let second = if power == 0 || power == 1 { 0.0 } else { n * (n - 1.0) * b.powi(power - 2) };
+
+            chain_jet(b.powi(power), first, second, inner)
+        },
+
+        Shape::Polynomial(coeffs) => {
+
+            let first: Vec<f64> = coeffs.iter().enumerate().skip(1).map(|(power, c)| power as f64 * c).collect();
+            let second: Vec<f64> = first.iter().enumerate().skip(1).map(|(power, c)| power as f64 * c).collect();
+
+            chain_jet(horner(&coeffs, x), horner(&first, x), horner(&second, x), input)
+        },
+
+        // Only values are cached, so the derivatives come from the inner function
+        Shape::Memo(memo) => eval_jet(&*memo.inner, input),
+        Shape::Named(_, inner) => eval_jet(&*inner, input),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => eval_jet(&*inner, input),
+
+        Shape::Variable(name) => panic!("Cannot evaluate free variable {} without an environment!", name),
+    }
+}
+
+fn eval_trace(function: &dyn FunctionTrait, x: f64, steps: &mut Vec<String>) -> f64 {
+
+    let mut binary = |left: &Function, right: &Function, symbol: &str, op: fn(f64, f64) -> f64| {
+
+        let l = eval_trace(&**left, x, steps);
+        let r = eval_trace(&**right, x, steps);
+        let value = op(l, r);
+
+        steps.push(format!("{:?} {} {:?} = {:?}", l, symbol, r, value));
+        value
+    };
+
+    match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(c)) => c,
+        Shape::Unary(UnaryFunction::Id) => x,
+        Shape::Variable(ref name) if name == "x" => x,
+
+        Shape::Sum(left, right) => binary(&left, &right, "+", |l, r| l + r),
+        Shape::Difference(left, right) => binary(&left, &right, "-", |l, r| l - r),
+        Shape::Product(left, right) => binary(&left, &right, "*", |l, r| l * r),
+        Shape::Quotient(top, bottom) => binary(&top, &bottom, "/", |t, b| t / b),
+
+        Shape::Negative(source) => {
+
+            let s = eval_trace(&*source, x, steps);
+            steps.push(format!("-{:?} = {:?}", s, -s));
+
+            -s
+        },
+
+        Shape::Unary(unary) => {
+
+            let value = unary.eval(&x);
+            steps.push(format!("{} = {:?}", unary.to_string().replace('$', &format!("{:?}", x)), value));
+
+            value
+        },
+
+        Shape::Opaque(opaque) => {
+
+            let value = opaque.eval(&x);
+            steps.push(format!("{} = {:?}", opaque.to_string().replace('$', &format!("{:?}", x)), value));
+
+            value
+        },
+
+        Shape::Composed(source, target) => {
+
+            let inner = eval_trace(&*target, x, steps);
+
+            eval_trace(&*source, inner, steps)
+        },
+
+        Shape::Clamp(source, lo, hi) => {
+
+            let s = eval_trace(&*source, x, steps);
+            let value = s.clamp(lo, hi);
+            steps.push(format!("clamp({:?}, {:?}, {:?}) = {:?}", s, lo, hi, value));
+
+            value
+        },
+
+        Shape::Piecewise(threshold, below, above) => {
+
+            if x < threshold { eval_trace(&*below, x, steps) } else { eval_trace(&*above, x, steps) }
+        },
+
+        Shape::Power(base, power) => {
+
+            let b = eval_trace(&*base, x, steps);
+            let value = b.powi(power);
+            steps.push(format!("{:?}^{} = {:?}", b, power, value));
+
+            value
+        },
+
+        Shape::Polynomial(coeffs) => {
+
+            let value = horner(&coeffs, x);
+            steps.push(format!("{} = {:?}", polynomial_tree(&coeffs).to_string().replace('$', &format!("{:?}", x)), value));
+
+            value
+        },
+
+        // Looked up as a whole, so the inner function's own steps don't show
+        Shape::Memo(memo) => {
+
+            let value = memo.eval(&x);
+            steps.push(format!("{} = {:?}", memo.to_string().replace('$', &format!("{:?}", x)), value));
+
+            value
+        },
+
+        Shape::Named(_, inner) => eval_trace(&*inner, x, steps),
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => eval_trace(&*inner, x, steps),
+
+        Shape::Variable(name) => panic!("Cannot evaluate free variable {} without an environment!", name),
+    }
+}
+
+fn saturate(value: f64) -> f64 {
+
+    match value {
+
+        v if v.is_finite() => v,
+        v if v.is_sign_negative() => f64::MIN,
+        _ => f64::MAX,
+    }
+}
+
+// Plain evaluation, except that every node's value goes through guard before its parent
+// sees it, which can replace it or abort the whole evaluation
+fn eval_guarded<E>(function: &dyn FunctionTrait, x: f64, guard: &dyn Fn(&dyn FunctionTrait, f64) -> Result<f64, E>) -> Result<f64, E> {
+
+    let eval = |child: &Function| eval_guarded(&**child, x, guard);
+
+    let value = match Shape::of(function) {
+
+        Shape::Sum(left, right) => eval(&left)? + eval(&right)?,
+        Shape::Difference(left, right) => eval(&left)? - eval(&right)?,
+        Shape::Negative(source) => -eval(&source)?,
+        Shape::Product(left, right) => eval(&left)? * eval(&right)?,
+        Shape::Quotient(top, bottom) => eval(&top)? / eval(&bottom)?,
+        Shape::Composed(source, target) => eval_guarded(&*source, eval(&target)?, guard)?,
+        Shape::Clamp(source, lo, hi) => eval(&source)?.clamp(lo, hi),
+        Shape::Piecewise(threshold, below, above) => if x < threshold { eval(&below)? } else { eval(&above)? },
+        Shape::Power(base, power) => eval(&base)?.powi(power),
+        Shape::Polynomial(coeffs) => horner(&coeffs, x),
+        Shape::Memo(memo) => memo.eval(&x),
+        Shape::Named(_, inner) => eval(&inner)?,
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => eval(&inner)?,
+        Shape::Unary(unary) => unary.eval(&x),
+        Shape::Opaque(opaque) => opaque.eval(&x),
+        Shape::Variable(ref name) if name == "x" => x,
+        Shape::Variable(name) => panic!("Cannot evaluate free variable {} without an environment!", name),
+    };
+
+    guard(function, value)
+}
+
+fn lookup_var(env: &HashMap<String, f64>, name: &str) -> f64 {
+
+    match env.get(name) {
+
+        Some(value) => *value,
+        None => panic!("Variable {} is not bound!", name),
+    }
+}
+
+enum Shape {
+
+    Sum(Function, Function),
+    Difference(Function, Function),
+    Negative(Function),
+    Product(Function, Function),
+    Quotient(Function, Function),
+    Composed(Function, Function),
+    Unary(UnaryFunction),
+    Variable(String),
+    Clamp(Function, f64, f64),
+    Piecewise(f64, Function, Function),
+    Opaque(OpaqueFunction),
+    Power(Function, i32),
+    Polynomial(Vec<f64>),
+    Memo(MemoFunction),
+    Named(String, Function),
+    #[cfg(feature = "units")]
+    Unit(Function, Unit),
+}
+
+impl Shape {
+
+    fn of(function: &dyn FunctionTrait) -> Shape {
+
+        let mut visitor = ShapeVisitor { shape: None };
+        function.accept(&mut visitor);
+
+        visitor.shape.expect("Visitor was never called!")
+    }
+
+    fn children(&self) -> Vec<&Function> {
+
+        match self {
+
+            Shape::Sum(left, right) | Shape::Difference(left, right) | Shape::Product(left, right) => vec![left, right],
+            Shape::Quotient(top, bottom) => vec![top, bottom],
+            Shape::Composed(source, target) => vec![source, target],
+            Shape::Negative(source) | Shape::Clamp(source, _, _) | Shape::Power(source, _) => vec![source],
+            Shape::Piecewise(_, below, above) => vec![below, above],
+            Shape::Memo(memo) => vec![&memo.inner],
+            Shape::Named(_, inner) => vec![inner],
+            #[cfg(feature = "units")]
+            Shape::Unit(inner, _) => vec![inner],
+            Shape::Unary(_) | Shape::Variable(_) | Shape::Opaque(_) | Shape::Polynomial(_) => vec![],
+        }
+    }
+
+    fn label(&self) -> String {
+
+        match self {
+
+            Shape::Sum(_, _) => String::from("Sum"),
+            Shape::Difference(_, _) => String::from("Difference"),
+            Shape::Negative(_) => String::from("Negative"),
+            Shape::Product(_, _) => String::from("Product"),
+            Shape::Quotient(_, _) => String::from("Quotient"),
+            Shape::Composed(_, _) => String::from("Composed"),
+            Shape::Unary(UnaryFunction::Const(c)) => format!("Const({})", c),
+            Shape::Unary(UnaryFunction::Id) => String::from("Id"),
+            Shape::Unary(UnaryFunction::Sin) => String::from("Sin"),
+            Shape::Unary(UnaryFunction::Cos) => String::from("Cos"),
+            Shape::Unary(UnaryFunction::Exp) => String::from("Exp"),
+            Shape::Unary(UnaryFunction::Log) => String::from("Log"),
+            Shape::Unary(UnaryFunction::Gamma) => String::from("Gamma"),
+            Shape::Unary(UnaryFunction::Digamma) => String::from("Digamma"),
+            Shape::Unary(UnaryFunction::Erf) => String::from("Erf"),
+            Shape::Unary(UnaryFunction::Erfc) => String::from("Erfc"),
+            Shape::Variable(name) => format!("Variable({})", name),
+            Shape::Clamp(_, lo, hi) => format!("Clamp({}, {})", lo, hi),
+            Shape::Piecewise(threshold, _, _) => format!("Piecewise({})", threshold),
+            Shape::Opaque(opaque) => format!("Opaque({})", opaque.name),
+            Shape::Power(_, power) => format!("Power({})", power),
+            Shape::Polynomial(coeffs) => format!("Polynomial({:?})", coeffs),
+            Shape::Memo(_) => String::from("Memo"),
+            Shape::Named(name, _) => format!("Named({})", name),
+            #[cfg(feature = "units")]
+            Shape::Unit(_, unit) => format!("Unit({})", unit),
+        }
+    }
+
+    fn symbol(&self) -> String {
+
+        match self {
+
+            Shape::Sum(_, _) => String::from("+"),
+            Shape::Difference(_, _) => String::from("-"),
+            Shape::Negative(_) => String::from("neg"),
+            Shape::Product(_, _) => String::from("*"),
+            Shape::Quotient(_, _) => String::from("/"),
+            Shape::Composed(_, _) => String::from("of"),
+            Shape::Unary(UnaryFunction::Const(c)) => c.to_string(),
+            Shape::Unary(UnaryFunction::Id) => String::from("x"),
+            Shape::Unary(UnaryFunction::Sin) => String::from("sin"),
+            Shape::Unary(UnaryFunction::Cos) => String::from("cos"),
+            Shape::Unary(UnaryFunction::Exp) => String::from("exp"),
+            Shape::Unary(UnaryFunction::Log) => String::from("ln"),
+            Shape::Unary(UnaryFunction::Gamma) => String::from("Γ"),
+            Shape::Unary(UnaryFunction::Digamma) => String::from("ψ"),
+            Shape::Unary(UnaryFunction::Erf) => String::from("erf"),
+            Shape::Unary(UnaryFunction::Erfc) => String::from("erfc"),
+            Shape::Variable(name) => name.clone(),
+            Shape::Clamp(_, lo, hi) => format!("clamp [{}, {}]", lo, hi),
+            Shape::Piecewise(threshold, _, _) => format!("x < {}", threshold),
+            Shape::Opaque(opaque) => opaque.name.clone(),
+            Shape::Power(_, power) => format!("^{}", power),
+            Shape::Polynomial(coeffs) => polynomial_tree(coeffs).to_string().replace('$', "x"),
+            Shape::Memo(_) => String::from("memo"),
+            Shape::Named(name, _) => name.clone(),
+            #[cfg(feature = "units")]
+            Shape::Unit(_, unit) => format!("[{}]", unit),
+        }
+    }
+
+    fn map<F: FnMut(&Function) -> Function>(self, mut f: F) -> Function {
+
+        match self {
+
+            Shape::Sum(left, right) => f(&left).add(f(&right)),
+            Shape::Difference(left, right) => f(&left).sub(f(&right)),
+            Shape::Negative(source) => f(&source).neg(),
+            Shape::Product(left, right) => f(&left).mul(f(&right)),
+            Shape::Quotient(top, bottom) => f(&top).div(f(&bottom)),
+            Shape::Composed(source, target) => f(&source).of(f(&target)),
+            Shape::Unary(unary) => unary.new(),
+            Shape::Variable(name) => VariableFunction::new(&name),
+            Shape::Clamp(source, lo, hi) => ClampFunction::new(f(&source), lo, hi),
+            Shape::Piecewise(threshold, below, above) => PiecewiseFunction::new(threshold, f(&below), f(&above)),
+            Shape::Opaque(opaque) => Rc::new(opaque),
+            Shape::Power(base, power) => IntPowFunction::new(f(&base), power),
+            Shape::Polynomial(coeffs) => PolynomialFunction::new(coeffs),
+
+            // An unchanged inner function keeps the cache, anything else would make it stale
+            Shape::Memo(memo) => {
+
+                let inner = f(&memo.inner);
+
+                if Rc::ptr_eq(&inner, &memo.inner) { Rc::new(memo) } else { MemoFunction::new(inner) }
+            },
+
+            Shape::Named(name, inner) => NamedFunction::new(&name, f(&inner)),
+            #[cfg(feature = "units")]
+            Shape::Unit(inner, unit) => UnitFunction::new(f(&inner), unit),
+        }
+    }
+}
+
+struct ShapeVisitor {
+
+    shape: Option<Shape>,
+}
+
+impl Visitor for ShapeVisitor {
+
+    fn visit_sum(&mut self, left: &Function, right: &Function) { self.shape = Some(Shape::Sum(Rc::clone(left), Rc::clone(right))); }
+    fn visit_difference(&mut self, left: &Function, right: &Function) { self.shape = Some(Shape::Difference(Rc::clone(left), Rc::clone(right))); }
+    fn visit_negative(&mut self, source: &Function) { self.shape = Some(Shape::Negative(Rc::clone(source))); }
+    fn visit_product(&mut self, left: &Function, right: &Function) { self.shape = Some(Shape::Product(Rc::clone(left), Rc::clone(right))); }
+    fn visit_quotient(&mut self, top: &Function, bottom: &Function) { self.shape = Some(Shape::Quotient(Rc::clone(top), Rc::clone(bottom))); }
+    fn visit_composed(&mut self, source: &Function, target: &Function) { self.shape = Some(Shape::Composed(Rc::clone(source), Rc::clone(target))); }
+    fn visit_unary(&mut self, unary: &UnaryFunction) { self.shape = Some(Shape::Unary(*unary)); }
+    fn visit_variable(&mut self, name: &str) { self.shape = Some(Shape::Variable(String::from(name))); }
+    fn visit_clamp(&mut self, source: &Function, lo: f64, hi: f64) { self.shape = Some(Shape::Clamp(Rc::clone(source), lo, hi)); }
+    fn visit_piecewise(&mut self, threshold: f64, below: &Function, above: &Function) { self.shape = Some(Shape::Piecewise(threshold, Rc::clone(below), Rc::clone(above))); }
+    fn visit_opaque(&mut self, opaque: &OpaqueFunction) { self.shape = Some(Shape::Opaque(opaque.clone())); }
+    fn visit_power(&mut self, base: &Function, power: i32) { self.shape = Some(Shape::Power(Rc::clone(base), power)); }
+    fn visit_polynomial(&mut self, coeffs: &[f64]) { self.shape = Some(Shape::Polynomial(coeffs.to_vec())); }
+    fn visit_memo(&mut self, memo: &MemoFunction) { self.shape = Some(Shape::Memo(memo.clone())); }
+    fn visit_named(&mut self, name: &str, inner: &Function) { self.shape = Some(Shape::Named(String::from(name), Rc::clone(inner))); }
+    #[cfg(feature = "units")]
+    fn visit_unit(&mut self, unit: &Unit, inner: &Function) { self.shape = Some(Shape::Unit(Rc::clone(inner), unit.clone())); }
+}
+
+fn grid(a: f64, b: f64, samples: usize) -> impl Iterator<Item = f64> {
+
+    (0..samples).map(move |i| if samples == 1 { (a + b) / 2.0 } else { a + (b - a) * i as f64 / (samples - 1) as f64 })
+}
+
+const NEWTON_MAX_ITER: usize = 100;
+
+const CRITICAL_POINT_TOL: f64 = 1e-10;
+
+const TAYLOR_BOUND_SAMPLES: usize = 100;
+
+fn newton(f: &dyn FunctionTrait, derivative: &dyn FunctionTrait, guess: f64, max_iter: usize, tol: f64) -> Option<f64> {
+
+    let mut x = guess;
+
+    for _ in 0..max_iter {
+
+        let value = f.eval(&x);
+
+        if value == 0.0 {
+
+            return Some(x);
+        }
+
+        let slope = derivative.eval(&x);
+
+        if slope == 0.0 || !slope.is_finite() {
+
+            return None;
+        }
+
+        let step = value / slope;
+        x -= step;
+
+        if !x.is_finite() {
+
+            return None;
+        }
+
+        if step.abs() < tol {
+
+            return Some(x);
+        }
+    }
+
+    None
+}
+
+pub trait FunctionOf {
+
+    fn of(self, other: Self) -> Self;
+}
+
+pub trait FunctionAdd {
+
+    fn add(self, other: Self) -> Self;
+}
+
+pub trait FunctionSub {
+
+    fn sub(self, other: Self) -> Self;
+}
+
+pub trait FunctionNeg {
+
+    fn neg(self) -> Self;
+}
+
+pub trait FunctionMul {
+
+    fn mul(self, other: Self) -> Self;
+}
+
+pub trait FunctionDiv {
+
+    fn div(self, other: Self) -> Self;
+}
+
+// The checked version of FunctionDiv, for callers that would rather fail at construction
+// than evaluate to inf/NaN everywhere
+pub trait FunctionTryDiv: Sized {
+
+    fn try_div(self, other: Self) -> Result<Self, BuildError>;
+}
+
+// Function is an alias for an Rc so it can't hold inherent constructors, and From<f64>
+// would clash with the blanket From<T> for T
+pub trait FunctionConst {
+
+    fn zero() -> Self;
+    fn constant(value: f64) -> Self;
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum BuildError {
+
+    DivisionByZero,
+}
+
+impl fmt::Display for BuildError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        match self {
+
+            BuildError::DivisionByZero => write!(f, "Division by a literal zero"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum DiffError {
+
+    NoDerivative(String),
+}
+
+impl fmt::Display for DiffError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        match self {
+
+            DiffError::NoDerivative(name) => write!(f, "No symbolic derivative for {}", name),
+        }
+    }
+}
+
+impl std::error::Error for DiffError {}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NanPolicy {
+
+    Propagate,
+    ZeroFill,
+    Error,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum EvalError {
+
+    NonFinite(String, f64),
+}
+
+impl fmt::Display for EvalError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        match self {
+
+            EvalError::NonFinite(node, value) => write!(f, "{} evaluated to {}", node, value),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+pub trait FunctionClosure {
+
+    fn as_closure(&self) -> Box<dyn Fn(f64) -> f64>;
+}
+
+pub trait FunctionCompose {
+
+    fn compose(&self, inner: &Self) -> Self;
+}
+
+impl FunctionOf for Function {
+
+    fn of(self, other: Function) -> Function {
+
+        ComposedFunction::new(self, other)
+    }
+}
+
+impl FunctionAdd for Function {
+
+    fn add(self, other: Function) -> Function {
+
+        SumFunction::new(self, other)
+    }
+}
+
+impl FunctionSub for Function {
+
+    fn sub(self, other: Function) -> Function {
+
+        DifferenceFunction::new(self, other)
+    }
+}
+
+impl FunctionNeg for Function {
+
+    fn neg(self) -> Function {
+
+        NegativeFunction::new(self)
+    }
+}
+
+impl FunctionMul for Function {
+
+    fn mul(self, other: Function) -> Function {
+
+        ProductFunction::new(self, other)
+    }
+}
+
+impl FunctionDiv for Function {
+
+    fn div(self, other: Function) -> Function {
+
+        QuotientFunction::new(self, other)
+    }
+}
+
+impl FunctionTryDiv for Function {
+
+    fn try_div(self, other: Function) -> Result<Function, BuildError> {
+
+        if other.is_zero() { Err(BuildError::DivisionByZero) } else { Ok(self.div(other)) }
+    }
+}
+
+impl FunctionConst for Function {
+
+    fn zero() -> Function {
+
+        UnaryFunction::Const(0.0).new()
+    }
+
+    fn constant(value: f64) -> Function {
+
+        UnaryFunction::Const(value).new()
+    }
+}
+
+impl FunctionCompose for Function {
+
+    fn compose(&self, inner: &Function) -> Function {
+
+        ComposedFunction::new(Rc::clone(self), Rc::clone(inner))
+    }
+}
+
+impl FunctionClosure for Function {
+
+    fn as_closure(&self) -> Box<dyn Fn(f64) -> f64> {
+
+        let function = Rc::clone(self);
+
+        Box::new(move |x| function.eval(&x))
+    }
+}
+
+pub struct SumFunction {
+
+    left: Function,
+    right: Function,
+}
+
+impl SumFunction {
+
+    pub fn new(left: Function, right: Function) -> Function {
+
+        Rc::new(SumFunction { left, right })
+    }
+
+    pub fn from_many(many: &[Function]) -> Function {
+
+        match many {
+
+            [] => panic!("Cannot sum an empty list!"),
+            [single] => Rc::clone(single),
+            [left, right] => SumFunction::new(Rc::clone(left), Rc::clone(right)),
+            _ => SumFunction::new(Rc::clone(&many[0]), SumFunction::from_many(&many[1..])),
+        }
+    }
+}
+
+impl FunctionTrait for SumFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.left.eval(x) + self.right.eval(x)
+    }
+
+    fn diff(&self) -> Function {
+        
+        self.left.diff().add(self.right.diff())
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        let mut result = Vec::new();
+
+        for exp in self.left.expand_vec() {
+
+            result.push(exp);
+        }
+
+        for exp in self.right.expand_vec() {
+
+            result.push(exp);
+        }
+
+        result 
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_sum(&self.left, &self.right);
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.left.eval_env(env) + self.right.eval_env(env)
+    }
+}
+
+impl fmt::Display for SumFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "({}) + ({})", self.left, self.right)
+    }
+}
+
+pub struct DifferenceFunction {
+
+    left: Function,
+    right: Function,
+}
+
+impl DifferenceFunction {
+
+    fn new(left: Function, right: Function) -> Function {
+
+        Rc::new(DifferenceFunction { left, right })
+    }
+}
+
+impl FunctionTrait for DifferenceFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.left.eval(x) - self.right.eval(x)
+    }
+
+    fn diff(&self) -> Function {
+        
+        self.left.diff().sub(self.right.diff())
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        let mut result = Vec::new();
+
+        for exp in self.left.expand_vec() {
+
+            result.push(exp);
+        }
+
+        for exp in self.right.expand_vec() {
+
+            result.push(exp.neg());
+        }
+
+        result 
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_difference(&self.left, &self.right);
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.left.eval_env(env) - self.right.eval_env(env)
+    }
+}
+
+impl fmt::Display for DifferenceFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "({}) - ({})", self.left, self.right)
+    }
+}
+
+pub struct NegativeFunction {
+
+    source: Function,
+}
+
+impl NegativeFunction {
+
+    fn new(source: Function) -> Function {
+
+        Rc::new(NegativeFunction { source })
+    }
+}
+
+impl FunctionTrait for NegativeFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        -self.source.eval(x)
+    }
+
+    fn diff(&self) -> Function {
+
+        self.source.diff().neg()
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        self.source.expand_vec().iter().map(|term| { Rc::clone(term).neg() }).collect()
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_negative(&self.source);
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        -self.source.eval_env(env)
+    }
+}
+
+impl fmt::Display for NegativeFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "-({})", self.source)
+    }
+}
+
+pub struct ProductFunction {
+
+    left: Function,
+    right: Function,
+}
+
+impl ProductFunction {
+
+    pub fn new(left: Function, right: Function) -> Function {
+
+        Rc::new(ProductFunction { left, right })
+    }
+
+    pub fn from_many(many: &[Function]) -> Function {
+
+        match many {
+
+            [] => panic!("Cannot multiply an empty list!"),
+            [single] => Rc::clone(single),
+            [left, right] => ProductFunction::new(Rc::clone(left), Rc::clone(right)),
+            _ => ProductFunction::new(Rc::clone(&many[0]), ProductFunction::from_many(&many[1..])),
+        }
+    }
+}
+
+impl FunctionTrait for ProductFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.left.eval(x) * self.right.eval(x)
+    }
+
+    fn diff(&self) -> Function {
+
+        let l_diff = self.left.diff();
+        let r_diff = self.right.diff();
+
+        let l_clone = Rc::clone(&self.left);
+        let r_clone = Rc::clone(&self.right);
+
+        let l_term = l_diff.mul(r_clone);
+        let r_term = r_diff.mul(l_clone);
+
+        l_term.add(r_term)
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        let l_exps = self.left.expand_vec();
+        let r_exps = self.right.expand_vec();
+
+        let mut result = Vec::new();
+
+        for l_exp in &l_exps {
+
+            for r_exp in &r_exps {
+                
+                result.push(Rc::clone(l_exp).mul(Rc::clone(r_exp)));
+            }
+        }
+
+        result
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_product(&self.left, &self.right);
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.left.eval_env(env) * self.right.eval_env(env)
+    }
+}
+
+impl fmt::Display for ProductFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        // A constant factor is written first as a coefficient, like 2sin(x)
+        match (const_value(&*self.left), const_value(&*self.right)) {
+
+            (Some(_), Some(_)) => write!(f, "{}*{}", self.left, self.right),
+            (Some(c), None) => write!(f, "{}{}", c, coefficient_operand(&*self.right)),
+            (None, Some(c)) => write!(f, "{}{}", c, coefficient_operand(&*self.left)),
+            (None, None) => write!(f, "{}*{}", product_factor(&*self.left), product_factor(&*self.right)),
+        }
+    }
+}
+
+fn product_factor(function: &dyn FunctionTrait) -> String {
+
+    if is_atomic_factor(function) { function.to_string() } else { format!("({})", function) }
+}
+
+// Compositions print as their source with the target substituted in, so they're only as atomic as the source
+fn is_atomic_factor(function: &dyn FunctionTrait) -> bool {
+
+    match Shape::of(function) {
+
+        Shape::Composed(source, target) => match Shape::of(&*source) {
+
+            Shape::Unary(UnaryFunction::Id) => is_atomic_factor(&*target),
+            Shape::Variable(ref name) if name == "x" => is_atomic_factor(&*target),
+            _ => is_atomic_factor(&*source),
+        },
+        Shape::Memo(memo) => is_atomic_factor(&*memo.inner),
+        Shape::Named(_, _) => true,
+        #[cfg(feature = "units")]
+        Shape::Unit(inner, _) => is_atomic_factor(&*inner),
+        Shape::Unary(_) | Shape::Variable(_) | Shape::Opaque(_) | Shape::Clamp(_, _, _) | Shape::Product(_, _) | Shape::Power(_, _) => true,
+        _ => false,
+    }
+}
+
+// Anything starting like a number would run into the coefficient, so it gets brackets
+fn coefficient_operand(function: &dyn FunctionTrait) -> String {
+
+    let factor = product_factor(function);
+
+    if starts_like_number(&factor) { format!("({})", factor) } else { factor }
+}
+
+fn starts_like_number(text: &str) -> bool {
+
+    matches!(text.chars().next(), Some(first) if first.is_ascii_digit() || first == '.' || first == '-')
+}
+
+pub struct QuotientFunction {
+
+    top: Function,
+    bottom: Function,
+}
+
+impl QuotientFunction {
+
+    fn new(top: Function, bottom: Function) -> Function {
+
+        Rc::new(QuotientFunction { top, bottom })
+    }
+}
+
+impl FunctionTrait for QuotientFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.top.eval(x) / self.bottom.eval(x)
+    }
+
+    fn diff(&self) -> Function {
+
+        let t_diff = self.top.diff();
+        let b_diff = self.bottom.diff();
+
+        let b_sqr = Rc::clone(&self.bottom).mul(Rc::clone(&self.bottom));
+
+        let l_term = t_diff.mul(Rc::clone(&self.bottom));
+        let r_term = b_diff.mul(Rc::clone(&self.top));
+
+        (l_term.sub(r_term)).div(b_sqr)
+    }
+
+    // Only the numerator is expanded, every term shares the one denominator as a single
+    // unexpanded factor. A quotient denominator c / d is folded first, so each term is
+    // (t * d) / c and no denominator is itself a quotient
+    fn expand_vec(&self) -> Vec<Function> {
+
+        let (t_exps, bottom) = match Shape::of(&*self.bottom) {
+
+            Shape::Quotient(c, d) => (Rc::clone(&self.top).mul(d).expand_vec(), c),
+            _ => (self.top.expand_vec(), Rc::clone(&self.bottom)),
+        };
+
+        let mut result = Vec::new();
+
+        for t_exp in &t_exps {
+
+            result.push(Rc::clone(t_exp).div(Rc::clone(&bottom)));
+        }
+
+        result
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_quotient(&self.top, &self.bottom);
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.top.eval_env(env) / self.bottom.eval_env(env)
+    }
+}
+
+impl fmt::Display for QuotientFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "({}) / ({})", self.top, self.bottom)
+    }
+}
+
+pub struct ComposedFunction {
+
+    target: Function,
+    source: Function,
+}
+
+impl ComposedFunction {
+
+    pub fn new(source: Function, target: Function) -> Function {
+
+        Rc::new(ComposedFunction { target, source })
+    }
+}
+
+impl FunctionTrait for ComposedFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.source.eval(&self.target.eval(x))
+    }
+
+    fn diff(&self) -> Function {
+
+        let s_diff = self.source.diff();
+        let t_diff = self.target.diff();
 
         let t_clone = Rc::clone(&self.target);
 
-        s_diff.of(t_clone).mul(t_diff)
+        s_diff.of(t_clone).mul(t_diff)
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        // TODO: Cancel inverses
+        vec![Rc::clone(&self.source).of(Rc::clone(&self.target))]
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_composed(&self.source, &self.target);
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        let mut inner = env.clone();
+        inner.insert(String::from("x"), self.target.eval_env(env));
+
+        self.source.eval_env(&inner)
+    }
+}
+
+impl fmt::Display for ComposedFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        let source_of_x = self.source.to_string();
+        let target_of_x = self.target.to_string();
+
+        // A name already prints its argument as x, so it's called on the target instead
+        if let Shape::Named(name, _) = Shape::of(&*self.source) {
+
+            return write!(f, "{}({})", name, target_of_x);
+        }
+
+        // A number pasted in bare could run into a coefficient (2$ of 3) or lose its sign
+        if source_of_x == "$" || (is_atomic_factor(&*self.target) && !starts_like_number(&target_of_x)) {
+
+            return write!(f, "{}", source_of_x.replace("$", &target_of_x));
+        }
+
+        // A bare $ (like in a product) needs brackets around the target to keep its meaning
+        let bracketed = format!("({})", target_of_x);
+        let chars: Vec<char> = source_of_x.chars().collect();
+        let mut result = String::new();
+
+        for (i, c) in chars.iter().enumerate() {
+
+            if *c != '$' {
+
+                result.push(*c);
+                continue;
+            }
+
+            let enclosed = i > 0 && chars[i - 1] == '(' && chars.get(i + 1) == Some(&')');
+            result.push_str(if enclosed { &target_of_x } else { &bracketed });
+        }
+
+        write!(f, "{}", result)
+    }
+}
+
+pub struct VariableFunction {
+
+    name: String,
+}
+
+impl VariableFunction {
+
+    pub fn new(name: &str) -> Function {
+
+        Rc::new(VariableFunction { name: String::from(name) })
+    }
+}
+
+impl FunctionTrait for VariableFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        match self.name.as_str() {
+
+            "x" => *x,
+            name => panic!("Cannot evaluate free variable {} without an environment!", name),
+        }
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        lookup_var(env, &self.name)
+    }
+
+    fn diff(&self) -> Function {
+
+        match self.name.as_str() {
+
+            "x" => UnaryFunction::Const(1.0).new(),
+            _ => UnaryFunction::Const(0.0).new(),
+        }
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        vec![VariableFunction::new(&self.name)]
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_variable(&self.name);
+    }
+}
+
+impl fmt::Display for VariableFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        // x is the same as Id, so it has to be substitutable when composed
+        match self.name.as_str() {
+
+            "x" => write!(f, "$"),
+            name => write!(f, "{}", name),
+        }
+    }
+}
+
+pub struct ClampFunction {
+
+    source: Function,
+    lo: f64,
+    hi: f64,
+}
+
+impl ClampFunction {
+
+    pub fn new(source: Function, lo: f64, hi: f64) -> Function {
+
+        assert!(lo <= hi, "Cannot clamp to an empty range!");
+
+        Rc::new(ClampFunction { source, lo, hi })
+    }
+
+    // 1 inside [lo, hi) and 0 outside, as a function of the source value
+    fn gate(lo: f64, hi: f64) -> Function {
+
+        let zero = UnaryFunction::Const(0.0).new();
+        let one = UnaryFunction::Const(1.0).new();
+
+        PiecewiseFunction::new(lo, Rc::clone(&zero), PiecewiseFunction::new(hi, one, zero))
+    }
+}
+
+impl FunctionTrait for ClampFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.source.eval(x).clamp(self.lo, self.hi)
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.source.eval_env(env).clamp(self.lo, self.hi)
+    }
+
+    // Clamping has no derivative at the bounds themselves, so this treats them as clamped
+    // and otherwise gates the source derivative on whether the source value is in range
+    fn diff(&self) -> Function {
+
+        let gate = ClampFunction::gate(self.lo, self.hi).of(Rc::clone(&self.source));
+
+        self.source.diff().mul(gate)
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        vec![ClampFunction::new(Rc::clone(&self.source), self.lo, self.hi)]
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_clamp(&self.source, self.lo, self.hi);
+    }
+}
+
+impl fmt::Display for ClampFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "clamp({}, {}, {})", self.source, self.lo, self.hi)
+    }
+}
+
+pub struct IntPowFunction {
+
+    base: Function,
+    power: i32,
+}
+
+impl IntPowFunction {
+
+    pub fn new(base: Function, power: i32) -> Function {
+
+        Rc::new(IntPowFunction { base, power })
+    }
+}
+
+impl FunctionTrait for IntPowFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.base.eval(x).powi(self.power)
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.base.eval_env(env).powi(self.power)
+    }
+
+    fn diff(&self) -> Function {
+
+        if self.power == 0 {
+
+            return UnaryFunction::Const(0.0).new();
+        }
+
+        let lowered = IntPowFunction::new(Rc::clone(&self.base), self.power - 1);
+
+        UnaryFunction::Const(self.power as f64).new().mul(lowered).mul(self.base.diff())
+    }
+
+    // Positive powers expand like the repeated product they stand for
+    fn expand_vec(&self) -> Vec<Function> {
+
+        if self.power < 2 {
+
+            return vec![IntPowFunction::new(Rc::clone(&self.base), self.power)];
+        }
+
+        let factors: Vec<Function> = (0..self.power).map(|_| Rc::clone(&self.base)).collect();
+
+        ProductFunction::from_many(&factors).expand_vec()
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_power(&self.base, self.power);
+    }
+}
+
+impl fmt::Display for IntPowFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        let base = self.base.to_string();
+
+        // A product would read as only its last factor being raised
+        let bare = is_atomic_factor(&*self.base) && !matches!(Shape::of(&*self.base), Shape::Product(_, _)) && !base.starts_with('-');
+
+        if bare { write!(f, "{}^{}", base, self.power) } else { write!(f, "({})^{}", base, self.power) }
+    }
+}
+
+pub struct PiecewiseFunction {
+
+    threshold: f64,
+    below: Function,
+    above: Function,
+}
+
+impl PiecewiseFunction {
+
+    pub fn new(threshold: f64, below: Function, above: Function) -> Function {
+
+        Rc::new(PiecewiseFunction::wrap(threshold, below, above))
+    }
+
+    // Keeps the concrete type around for the join checks
+    pub fn wrap(threshold: f64, below: Function, above: Function) -> PiecewiseFunction {
+
+        PiecewiseFunction { threshold, below, above }
+    }
+
+    // Both branches evaluated at the threshold, so a branch only defined on its own side
+    // still has to extend continuously up to it
+    pub fn is_c1_continuous(&self, tol: f64) -> bool {
+
+        let t = self.threshold;
+        let values_match = (self.below.eval(&t) - self.above.eval(&t)).abs() <= tol;
+
+        values_match && (self.below.diff().eval(&t) - self.above.diff().eval(&t)).abs() <= tol
+    }
+}
+
+impl FunctionTrait for PiecewiseFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        if *x < self.threshold { self.below.eval(x) } else { self.above.eval(x) }
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        if lookup_var(env, "x") < self.threshold { self.below.eval_env(env) } else { self.above.eval_env(env) }
+    }
+
+    fn diff(&self) -> Function {
+
+        PiecewiseFunction::new(self.threshold, self.below.diff(), self.above.diff())
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        vec![PiecewiseFunction::new(self.threshold, Rc::clone(&self.below), Rc::clone(&self.above))]
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_piecewise(self.threshold, &self.below, &self.above);
+    }
+}
+
+impl fmt::Display for PiecewiseFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "(({}) if $ < {} else ({}))", self.below, self.threshold, self.above)
+    }
+}
+
+#[derive(Clone)]
+pub struct OpaqueFunction {
+
+    eval_fn: Rc<dyn Fn(f64) -> f64>,
+    diff_fn: Option<Rc<dyn Fn(f64) -> f64>>,
+    name: String,
+}
+
+impl OpaqueFunction {
+
+    pub fn new<F: Fn(f64) -> f64 + 'static>(name: &str, eval_fn: F) -> Function {
+
+        Rc::new(OpaqueFunction { eval_fn: Rc::new(eval_fn), diff_fn: None, name: String::from(name) })
+    }
+
+    pub fn with_diff<F, D>(name: &str, eval_fn: F, diff_fn: D) -> Function
+    where
+        F: Fn(f64) -> f64 + 'static,
+        D: Fn(f64) -> f64 + 'static,
+    {
+
+        Rc::new(OpaqueFunction { eval_fn: Rc::new(eval_fn), diff_fn: Some(Rc::new(diff_fn)), name: String::from(name) })
+    }
+
+    pub fn name(&self) -> &str {
+
+        &self.name
+    }
+
+    pub fn has_diff(&self) -> bool {
+
+        self.diff_fn.is_some()
+    }
+}
+
+impl FunctionTrait for OpaqueFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        (self.eval_fn)(*x)
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        (self.eval_fn)(lookup_var(env, "x"))
+    }
+
+    // Without a provided derivative this falls back to a central difference, so repeated
+    // differentiation loses precision quickly
+    fn diff(&self) -> Function {
+
+        let name = format!("{}'", self.name);
+
+        let diff_fn: Rc<dyn Fn(f64) -> f64> = match &self.diff_fn {
+
+            Some(diff_fn) => Rc::clone(diff_fn),
+            None => {
+
+                let eval_fn = Rc::clone(&self.eval_fn);
+
+                Rc::new(move |x: f64| central_difference(|t| eval_fn(t), x))
+            },
+        };
+
+        Rc::new(OpaqueFunction { eval_fn: diff_fn, diff_fn: None, name })
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        vec![Rc::new(self.clone())]
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_opaque(self);
+    }
+}
+
+impl fmt::Display for OpaqueFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "{}($)", self.name)
+    }
+}
+
+// Coefficients in increasing order of power, evaluated by Horner's method
+pub struct PolynomialFunction {
+
+    coeffs: Vec<f64>,
+}
+
+impl PolynomialFunction {
+
+    pub fn new(coeffs: Vec<f64>) -> Function {
+
+        Rc::new(PolynomialFunction { coeffs })
+    }
+
+    pub fn coeffs(&self) -> &[f64] {
+
+        &self.coeffs
+    }
+
+}
+
+// The equivalent sum of monomials, for the places that want a polynomial spelled out
+fn polynomial_tree(coeffs: &[f64]) -> Function {
+
+    let terms: Vec<Function> = coeffs.iter().enumerate().filter(|(_, c)| **c != 0.0).map(|(power, c)| monomial(*c, power)).collect();
+
+    if terms.is_empty() { UnaryFunction::Const(0.0).new() } else { SumFunction::from_many(&terms) }
+}
+
+impl FunctionTrait for PolynomialFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        horner(&self.coeffs, *x)
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        horner(&self.coeffs, lookup_var(env, "x"))
+    }
+
+    fn diff(&self) -> Function {
+
+        PolynomialFunction::new(self.coeffs.iter().enumerate().skip(1).map(|(power, c)| power as f64 * c).collect())
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        polynomial_tree(&self.coeffs).expand_vec()
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_polynomial(&self.coeffs);
+    }
+}
+
+impl fmt::Display for PolynomialFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "{}", polynomial_tree(&self.coeffs))
+    }
+}
+
+// An owned copy of a tree that evaluates with a plain match per node instead of a virtual
+// call through an Rc, which matters in hot loops; opaque functions and free variables
+// stay dynamic, since there's nothing to inline for them, and so do memoised functions so
+// they keep using their cache
+pub enum CompiledFunction {
+
+    Unary(UnaryFunction),
+    Sum(Box<CompiledFunction>, Box<CompiledFunction>),
+    Difference(Box<CompiledFunction>, Box<CompiledFunction>),
+    Negative(Box<CompiledFunction>),
+    Product(Box<CompiledFunction>, Box<CompiledFunction>),
+    Quotient(Box<CompiledFunction>, Box<CompiledFunction>),
+    Composed(Box<CompiledFunction>, Box<CompiledFunction>),
+    Clamp(Box<CompiledFunction>, f64, f64),
+    Piecewise(f64, Box<CompiledFunction>, Box<CompiledFunction>),
+    Power(Box<CompiledFunction>, i32),
+    Polynomial(Vec<f64>),
+    Dynamic(Function),
+}
+
+impl CompiledFunction {
+
+    fn of(function: &dyn FunctionTrait) -> CompiledFunction {
+
+        let compile = |child: &Function| Box::new(CompiledFunction::of(&**child));
+
+        match Shape::of(function) {
+
+            Shape::Unary(unary) => CompiledFunction::Unary(unary),
+            Shape::Variable(ref name) if name == "x" => CompiledFunction::Unary(UnaryFunction::Id),
+            Shape::Sum(left, right) => CompiledFunction::Sum(compile(&left), compile(&right)),
+            Shape::Difference(left, right) => CompiledFunction::Difference(compile(&left), compile(&right)),
+            Shape::Negative(source) => CompiledFunction::Negative(compile(&source)),
+            Shape::Product(left, right) => CompiledFunction::Product(compile(&left), compile(&right)),
+            Shape::Quotient(top, bottom) => CompiledFunction::Quotient(compile(&top), compile(&bottom)),
+            Shape::Composed(source, target) => CompiledFunction::Composed(compile(&source), compile(&target)),
+            Shape::Clamp(source, lo, hi) => CompiledFunction::Clamp(compile(&source), lo, hi),
+            Shape::Piecewise(threshold, below, above) => CompiledFunction::Piecewise(threshold, compile(&below), compile(&above)),
+            Shape::Power(base, power) => CompiledFunction::Power(compile(&base), power),
+            Shape::Polynomial(coeffs) => CompiledFunction::Polynomial(coeffs),
+            Shape::Named(_, inner) => CompiledFunction::of(&*inner),
+            #[cfg(feature = "units")]
+            Shape::Unit(inner, _) => CompiledFunction::of(&*inner),
+            shape @ Shape::Variable(_) | shape @ Shape::Opaque(_) | shape @ Shape::Memo(_) => CompiledFunction::Dynamic(shape.map(Rc::clone)),
+        }
+    }
+
+    pub fn eval(&self, x: f64) -> f64 {
+
+        match self {
+
+            CompiledFunction::Unary(unary) => unary.eval(&x),
+            CompiledFunction::Sum(left, right) => left.eval(x) + right.eval(x),
+            CompiledFunction::Difference(left, right) => left.eval(x) - right.eval(x),
+            CompiledFunction::Negative(source) => -source.eval(x),
+            CompiledFunction::Product(left, right) => left.eval(x) * right.eval(x),
+            CompiledFunction::Quotient(top, bottom) => top.eval(x) / bottom.eval(x),
+            CompiledFunction::Composed(source, target) => source.eval(target.eval(x)),
+            CompiledFunction::Clamp(source, lo, hi) => source.eval(x).clamp(*lo, *hi),
+            CompiledFunction::Piecewise(threshold, below, above) => if x < *threshold { below.eval(x) } else { above.eval(x) },
+            CompiledFunction::Power(base, power) => base.eval(x).powi(*power),
+            CompiledFunction::Polynomial(coeffs) => horner(coeffs, x),
+            CompiledFunction::Dynamic(function) => function.eval(&x),
+        }
+    }
+}
+
+// Clones share the cache
+#[derive(Clone)]
+pub struct MemoFunction {
+
+    inner: Function,
+    cache: Rc<RefCell<HashMap<u64, f64>>>,
+}
+
+impl MemoFunction {
+
+    pub fn new(inner: Function) -> Function {
+
+        Rc::new(MemoFunction::wrap(inner))
+    }
+
+    // Keeps the concrete type around so the cache can be inspected
+    pub fn wrap(inner: Function) -> MemoFunction {
+
+        MemoFunction { inner, cache: Rc::new(RefCell::new(HashMap::new())) }
+    }
+
+    pub fn inner(&self) -> &Function {
+
+        &self.inner
+    }
+
+    pub fn cache_len(&self) -> usize {
+
+        self.cache.borrow().len()
+    }
+}
+
+impl FunctionTrait for MemoFunction {
+
+    // Keyed on the bit pattern, so 0.0 and -0.0 are cached separately
+    fn eval(&self, x: &f64) -> f64 {
+
+        if let Some(value) = self.cache.borrow().get(&x.to_bits()) {
+
+            return *value;
+        }
+
+        let value = self.inner.eval(x);
+        self.cache.borrow_mut().insert(x.to_bits(), value);
+
+        value
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.inner.eval_env(env)
+    }
+
+    fn diff(&self) -> Function {
+
+        MemoFunction::new(self.inner.diff())
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        self.inner.expand_vec()
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_memo(self);
+    }
+}
+
+impl fmt::Display for MemoFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "{}", self.inner)
+    }
+}
+
+pub struct NamedFunction {
+
+    name: String,
+    inner: Function,
+}
+
+impl NamedFunction {
+
+    pub fn new(name: &str, inner: Function) -> Function {
+
+        Rc::new(NamedFunction::wrap(name, inner))
+    }
+
+    pub fn wrap(name: &str, inner: Function) -> NamedFunction {
+
+        NamedFunction { name: String::from(name), inner }
+    }
+
+    pub fn name(&self) -> &str {
+
+        &self.name
+    }
+
+    pub fn unfold(&self) -> Function {
+
+        Rc::clone(&self.inner)
+    }
+}
+
+impl FunctionTrait for NamedFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.inner.eval(x)
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.inner.eval_env(env)
+    }
+
+    fn diff(&self) -> Function {
+
+        self.inner.diff()
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        self.inner.expand_vec()
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_named(&self.name, &self.inner);
+    }
+}
+
+// Printed as a call on x like "g(x)", a composition fills in its own argument instead
+impl fmt::Display for NamedFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "{}(x)", self.name)
+    }
+}
+
+// Exponents of named base dimensions, missing names having exponent zero
+#[cfg(feature = "units")]
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Unit {
+
+    exponents: BTreeMap<String, i32>,
+}
+
+#[cfg(feature = "units")]
+impl Unit {
+
+    pub fn dimensionless() -> Unit {
+
+        Unit::default()
+    }
+
+    pub fn base(name: &str) -> Unit {
+
+        let mut exponents = BTreeMap::new();
+        exponents.insert(String::from(name), 1);
+
+        Unit { exponents }
+    }
+
+    pub fn is_dimensionless(&self) -> bool {
+
+        self.exponents.is_empty()
+    }
+
+    pub fn times(&self, other: &Unit) -> Unit {
+
+        self.combine(other, 1)
+    }
+
+    pub fn per(&self, other: &Unit) -> Unit {
+
+        self.combine(other, -1)
+    }
+
+    pub fn powi(&self, power: i32) -> Unit {
+
+        (0..power.abs()).fold(Unit::dimensionless(), |acc, _| acc.combine(self, power.signum()))
+    }
+
+    fn combine(&self, other: &Unit, sign: i32) -> Unit {
+
+        let mut exponents = self.exponents.clone();
+
+        for (name, power) in &other.exponents {
+
+            let entry = exponents.entry(name.clone()).or_insert(0);
+            *entry += sign * power;
+
+            if *entry == 0 {
+
+                exponents.remove(name);
+            }
+        }
+
+        Unit { exponents }
+    }
+}
+
+#[cfg(feature = "units")]
+impl fmt::Display for Unit {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.is_dimensionless() {
+
+            return write!(f, "1");
+        }
+
+        let factors: Vec<String> = self.exponents.iter().map(|(name, power)| match power {
+
+            1 => name.clone(),
+            _ => format!("{}^{}", name, power),
+
+        }).collect();
+
+        write!(f, "{}", factors.join(" "))
+    }
+}
+
+#[cfg(feature = "units")]
+#[derive(Clone, PartialEq, Debug)]
+pub enum UnitError {
+
+    NotDimensionless(String, Unit),
+    Mismatch(Unit, Unit),
+}
+
+#[cfg(feature = "units")]
+impl fmt::Display for UnitError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        match self {
+
+            UnitError::NotDimensionless(name, unit) => write!(f, "{} needs a dimensionless argument, got {}", name, unit),
+            UnitError::Mismatch(left, right) => write!(f, "Cannot combine {} with {}", left, right),
+        }
+    }
+}
+
+#[cfg(feature = "units")]
+impl std::error::Error for UnitError {}
+
+// Annotates a subexpression with the unit it's meant to have, everything but unit
+// inference sees straight through to the inner function
+#[cfg(feature = "units")]
+pub struct UnitFunction {
+
+    inner: Function,
+    unit: Unit,
+}
+
+#[cfg(feature = "units")]
+impl UnitFunction {
+
+    pub fn new(inner: Function, unit: Unit) -> Function {
+
+        Rc::new(UnitFunction { inner, unit })
+    }
+}
+
+#[cfg(feature = "units")]
+impl FunctionTrait for UnitFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        self.inner.eval(x)
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        self.inner.eval_env(env)
+    }
+
+    // The derivative's unit depends on the unit of x, which isn't known here, so it's
+    // left unannotated for inference to work out
+    fn diff(&self) -> Function {
+
+        self.inner.diff()
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        self.inner.expand_vec()
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_unit(&self.unit, &self.inner);
+    }
+}
+
+#[cfg(feature = "units")]
+impl fmt::Display for UnitFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        write!(f, "{}", self.inner)
+    }
+}
+
+// None means the unit is free, as for constants and unannotated variables
+#[cfg(feature = "units")]
+fn infer_unit(function: &dyn FunctionTrait, x_unit: &Option<Unit>) -> Result<Option<Unit>, UnitError> {
+
+    let inferred = match Shape::of(function) {
+
+        Shape::Unary(UnaryFunction::Const(_)) => None,
+        Shape::Unary(UnaryFunction::Id) => x_unit.clone(),
+        Shape::Variable(ref name) if name == "x" => x_unit.clone(),
+        Shape::Variable(_) => None,
+
+        Shape::Unary(unary) => dimensionless_of(intrinsic_name(unary).unwrap_or("unary"), x_unit)?,
+        Shape::Opaque(opaque) => dimensionless_of(opaque.name(), x_unit)?,
+
+        Shape::Sum(left, right) | Shape::Difference(left, right) => {
+
+            same_unit(infer_unit(&*left, x_unit)?, infer_unit(&*right, x_unit)?)?
+        },
+
+        Shape::Piecewise(_, below, above) => same_unit(infer_unit(&*below, x_unit)?, infer_unit(&*above, x_unit)?)?,
+        Shape::Negative(source) | Shape::Clamp(source, _, _) => infer_unit(&*source, x_unit)?,
+
+        Shape::Product(left, right) => match (infer_unit(&*left, x_unit)?, infer_unit(&*right, x_unit)?) {
+
+            (Some(l), Some(r)) => Some(l.times(&r)),
+            (l, r) => l.or(r),
+        },
+
+        Shape::Quotient(top, bottom) => match (infer_unit(&*top, x_unit)?, infer_unit(&*bottom, x_unit)?) {
+
+            (Some(t), Some(b)) => Some(t.per(&b)),
+            (t, None) => t,
+            (None, Some(b)) => Some(Unit::dimensionless().per(&b)),
+        },
+
+        Shape::Composed(source, target) => infer_unit(&*source, &infer_unit(&*target, x_unit)?)?,
+        Shape::Power(base, power) => infer_unit(&*base, x_unit)?.map(|unit| unit.powi(power)),
+        Shape::Polynomial(coeffs) => infer_unit(&*polynomial_tree(&coeffs), x_unit)?,
+        Shape::Memo(memo) => infer_unit(&*memo.inner, x_unit)?,
+        Shape::Named(_, inner) => infer_unit(&*inner, x_unit)?,
+        Shape::Unit(inner, declared) => same_unit(Some(declared), infer_unit(&*inner, x_unit)?)?,
+    };
+
+    Ok(inferred)
+}
+
+#[cfg(feature = "units")]
+fn dimensionless_of(name: &str, x_unit: &Option<Unit>) -> Result<Option<Unit>, UnitError> {
+
+    match x_unit {
+
+        Some(unit) if !unit.is_dimensionless() => Err(UnitError::NotDimensionless(String::from(name), unit.clone())),
+        _ => Ok(Some(Unit::dimensionless())),
+    }
+}
+
+#[cfg(feature = "units")]
+fn same_unit(left: Option<Unit>, right: Option<Unit>) -> Result<Option<Unit>, UnitError> {
+
+    match (left, right) {
+
+        (Some(l), Some(r)) if l != r => Err(UnitError::Mismatch(l, r)),
+        (l, r) => Ok(l.or(r)),
+    }
+}
+
+pub struct VectorFunction {
+
+    components: Vec<Function>,
+}
+
+impl VectorFunction {
+
+    pub fn new(components: Vec<Function>) -> VectorFunction {
+
+        VectorFunction { components }
+    }
+
+    pub fn components(&self) -> &[Function] {
+
+        &self.components
+    }
+
+    pub fn eval_vec(&self, x: f64) -> Vec<f64> {
+
+        self.components.iter().map(|component| component.eval(&x)).collect()
+    }
+
+    pub fn diff(&self) -> VectorFunction {
+
+        VectorFunction::new(self.components.iter().map(|component| component.diff()).collect())
+    }
+
+    // Entry [i][j] is the partial derivative of component i with respect to vars[j]
+    pub fn jacobian(&self, vars: &[&str]) -> Vec<Vec<Function>> {
+
+        self.components.iter().map(|component| vars.iter().map(|var| component.partial_diff(var).simplify_fully()).collect()).collect()
+    }
+
+    // Every variable the components depend on, x included, in sorted order
+    pub fn variables(&self) -> Vec<String> {
+
+        let mut names = BTreeSet::new();
+
+        for component in &self.components {
+
+            collect_variables(&**component, &mut names);
+        }
+
+        names.into_iter().collect()
+    }
+}
+
+impl fmt::Display for VectorFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        let components: Vec<String> = self.components.iter().map(|component| component.to_string()).collect();
+
+        write!(f, "[{}]", components.join(", "))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UnaryFunction {
+
+    Const(f64),
+    Id,
+    Sin,
+    Cos,
+    Exp,
+    Log,
+    Gamma,
+    Digamma,
+    Erf,
+    Erfc,
+}
+
+impl UnaryFunction {
+
+    pub fn new(self) -> Function {
+
+        Rc::new(self)
+    }
+}
+
+impl FunctionTrait for UnaryFunction {
+
+    fn eval(&self, x: &f64) -> f64 {
+
+        match self {
+
+            UnaryFunction::Const(c) => *c,
+            UnaryFunction::Id => *x,
+            UnaryFunction::Sin => x.sin(),
+            UnaryFunction::Cos => x.cos(),
+            UnaryFunction::Exp => x.exp(),
+            UnaryFunction::Log => x.ln(),
+            UnaryFunction::Gamma => gamma(*x),
+            UnaryFunction::Digamma => digamma(*x),
+            UnaryFunction::Erf => erf(*x),
+            UnaryFunction::Erfc => erfc(*x),
+        }
+    }
+
+    fn diff(&self) -> Function {
+
+        match self {
+            
+            UnaryFunction::Const(_) => UnaryFunction::Const(0.0).new(),
+            UnaryFunction::Id => UnaryFunction::Const(1.0).new(),
+            UnaryFunction::Sin => UnaryFunction::Cos.new(),
+            UnaryFunction::Cos => UnaryFunction::Sin.new().neg(),
+            UnaryFunction::Exp => UnaryFunction::Exp.new(),
+            UnaryFunction::Log => UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new()),
+            UnaryFunction::Gamma => UnaryFunction::Gamma.new().mul(UnaryFunction::Digamma.new()),
+            UnaryFunction::Digamma => OpaqueFunction::new("trigamma", trigamma),
+            UnaryFunction::Erf => erf_diff(),
+            UnaryFunction::Erfc => erf_diff().neg(),
+        }
+    }
+
+    fn expand_vec(&self) -> Vec<Function> {
+
+        vec![self.new()]
+    }
+
+    fn accept(&self, visitor: &mut dyn Visitor) {
+
+        visitor.visit_unary(self);
+    }
+
+    fn eval_env(&self, env: &HashMap<String, f64>) -> f64 {
+
+        match self {
+
+            UnaryFunction::Const(c) => *c,
+            _ => self.eval(&lookup_var(env, "x")),
+        }
+    }
+}
+
+impl fmt::Display for UnaryFunction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        let plain = match self {
+
+            UnaryFunction::Const(c) => c.to_string(),
+            UnaryFunction::Id => String::from("$"),
+            UnaryFunction::Sin => String::from("sin($)"),
+            UnaryFunction::Cos => String::from("cos($)"),
+            UnaryFunction::Exp => String::from("exp($)"),
+            UnaryFunction::Log => String::from("ln($)"),
+            UnaryFunction::Gamma => String::from("Γ($)"),
+            UnaryFunction::Digamma => String::from("ψ($)"),
+            UnaryFunction::Erf => String::from("erf($)"),
+            UnaryFunction::Erfc => String::from("erfc($)"),
+        };
+
+        write!(f, "{}", plain)
+    }
+}
+
+const LANCZOS_G: f64 = 7.0;
+
+const LANCZOS_COEFFICIENTS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+];
+
+fn gamma(x: f64) -> f64 {
+
+    if x < 0.5 {
+
+        // Reflection formula, since the Lanczos series only holds on the right half plane
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma(1.0 - x));
+    }
+
+    let z = x - 1.0;
+    let t = z + LANCZOS_G + 0.5;
+    let series = LANCZOS_COEFFICIENTS[1..].iter().enumerate().fold(LANCZOS_COEFFICIENTS[0], |sum, (i, c)| sum + c / (z + i as f64 + 1.0));
+
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(z + 0.5) * (-t).exp() * series
+}
+
+fn digamma(x: f64) -> f64 {
+
+    if x <= 0.0 && x == x.floor() {
+
+        return f64::NAN;
+    }
+
+    if x < 0.0 {
+
+        return digamma(1.0 - x) - std::f64::consts::PI / (std::f64::consts::PI * x).tan();
+    }
+
+    // Shift up with the recurrence until the asymptotic series is accurate
+    let mut x = x;
+    let mut shift = 0.0;
+
+    while x < 6.0 {
+
+        shift -= 1.0 / x;
+        x += 1.0;
+    }
+
+    let inv_sqr = 1.0 / (x * x);
+    let series = inv_sqr * (1.0 / 12.0 - inv_sqr * (1.0 / 120.0 - inv_sqr * (1.0 / 252.0 - inv_sqr * (1.0 / 240.0 - inv_sqr / 132.0))));
+
+    shift + x.ln() - 0.5 / x - series
+}
+
+fn trigamma(x: f64) -> f64 {
+
+    if x <= 0.0 && x == x.floor() {
+
+        return f64::NAN;
+    }
+
+    if x < 0.0 {
+
+        let sin_pi_x = (std::f64::consts::PI * x).sin();
+
+        return -trigamma(1.0 - x) + std::f64::consts::PI * std::f64::consts::PI / (sin_pi_x * sin_pi_x);
+    }
+
+    let mut x = x;
+    let mut shift = 0.0;
+
+    while x < 6.0 {
+
+        shift += 1.0 / (x * x);
+        x += 1.0;
+    }
+
+    let inv = 1.0 / x;
+    let inv_sqr = inv * inv;
+    let series = inv + inv_sqr / 2.0 + inv * inv_sqr * (1.0 / 6.0 - inv_sqr * (1.0 / 30.0 - inv_sqr * (1.0 / 42.0 - inv_sqr / 30.0)));
+
+    shift + series
+}
+
+// Below this the Taylor series converges without losing much to cancellation
+const ERF_SERIES_LIMIT: f64 = 3.0;
+
+fn erf(x: f64) -> f64 {
+
+    if x.abs() >= ERF_SERIES_LIMIT {
+
+        return x.signum() * (1.0 - erfc(x.abs()));
+    }
+
+    let x_sqr = x * x;
+    let mut term = x;
+    let mut sum = x;
+    let mut n = 0.0;
+
+    while term.abs() > 1e-17 * sum.abs() {
+
+        n += 1.0;
+        term *= -x_sqr / n;
+        sum += term / (2.0 * n + 1.0);
+    }
+
+    2.0 / std::f64::consts::PI.sqrt() * sum
+}
+
+fn erfc(x: f64) -> f64 {
+
+    if x < ERF_SERIES_LIMIT {
+
+        return 1.0 - erf(x);
+    }
+
+    // Chebyshev fit from Numerical Recipes, with fractional error below 1.2e-7
+    let t = 1.0 / (1.0 + 0.5 * x);
+    let poly = -1.265_512_23 + t * (1.000_023_68 + t * (0.374_091_96 + t * (0.096_784_18 + t * (-0.186_288_06
+        + t * (0.278_868_07 + t * (-1.135_203_98 + t * (1.488_515_87 + t * (-0.822_152_23 + t * 0.170_872_77))))))));
+
+    t * (-x * x + poly).exp()
+}
+
+fn erf_diff() -> Function {
+
+    let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+    let scale = UnaryFunction::Const(2.0 / std::f64::consts::PI.sqrt()).new();
+
+    scale.mul(UnaryFunction::Exp.new().of(x_sqr.neg()))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn eval_const() {
+
+        let two = UnaryFunction::Const(2.0).new();
+        assert_eq!(two.eval(&1.0), 2.0);
+    }
+
+    #[test]
+    fn eval_id() {
+
+        let id = UnaryFunction::Id.new();
+        assert_eq!(id.eval(&3.0), 3.0);
+    }
+
+    #[test]
+    fn eval_sin() {
+
+        let sin = UnaryFunction::Sin.new();
+        assert_eq!(sin.eval(&4.0), 4f64.sin());
+    }
+
+    #[test]
+    fn eval_cos() {
+
+        let cos = UnaryFunction::Cos.new();
+        assert_eq!(cos.eval(&5.0), 5f64.cos());
+    }
+
+    #[test]
+    fn eval_exp() {
+
+        let exp = UnaryFunction::Exp.new();
+        assert_eq!(exp.eval(&6.0), 6f64.exp());
+    }
+
+    #[test]
+    fn eval_log() {
+
+        let log = UnaryFunction::Log.new();
+        assert_eq!(log.eval(&7.0), 7f64.ln());
+    }
+
+    #[test]
+    fn eval_sum() {
+
+        let three = UnaryFunction::Const(3.0).new().add(UnaryFunction::Const(2.0).new());
+        assert_eq!(three.eval(&8.0), 5.0);
+    }
+
+    #[test]
+    fn eval_diff() {
+
+        let three_minus_x = UnaryFunction::Const(3.0).new().sub(UnaryFunction::Id.new());
+        assert_eq!(three_minus_x.eval(&9.0), -6.0);
+    }
+
+    #[test]
+    fn eval_neg() {
+
+        let minus_x = UnaryFunction::Id.new().neg();
+        assert_eq!(minus_x.eval(&10.0), -10.0);
+    }
+
+    #[test]
+    fn eval_mul() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        assert_eq!(x_sqr.eval(&11.0), 121.0);
+    }
+
+    #[test]
+    fn eval_div() {
+
+        let inv = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new());
+        assert_eq!(inv.eval(&12.0), 1.0 / 12.0);
+    }
+
+    #[test]
+    fn eval_comp() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let sin_of_sqr = UnaryFunction::Sin.new().of(x_sqr);
+        assert_eq!(sin_of_sqr.eval(&-1.0), 1f64.sin());
+    }
+
+    #[test]
+    fn eval_expand() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let x_plus_sqr = UnaryFunction::Id.new().add(x_sqr);
+        assert_eq!(x_plus_sqr.expand().eval(&-2.0), x_plus_sqr.eval(&-2.0));
+    }
+
+    #[test]
+    fn find_root_sqr() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let x_sqr_minus_two = x_sqr.sub(UnaryFunction::Const(2.0).new());
+        let root = x_sqr_minus_two.find_root(1.0, 100, 1e-12).unwrap();
+        assert!((root - 2f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn all_roots_sqr() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let x_sqr_minus_one = x_sqr.sub(UnaryFunction::Const(1.0).new());
+        let roots = x_sqr_minus_one.all_roots(-2.0, 2.0, 10, 1e-9);
+        assert_eq!(roots.len(), 2);
+        assert!((roots[0] + 1.0).abs() < 1e-6);
+        assert!((roots[1] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn visitor_counts_nodes() {
+
+        struct Leaves {
+
+            leaves: usize,
+            branches: usize,
+        }
+
+        impl Leaves {
+
+            fn branch(&mut self, children: &[&Function]) {
+
+                self.branches += 1;
+
+                for child in children {
+
+                    child.accept(self);
+                }
+            }
+        }
+
+        impl Visitor for Leaves {
+
+            fn visit_sum(&mut self, left: &Function, right: &Function) { self.branch(&[left, right]); }
+            fn visit_difference(&mut self, left: &Function, right: &Function) { self.branch(&[left, right]); }
+            fn visit_negative(&mut self, source: &Function) { self.branch(&[source]); }
+            fn visit_product(&mut self, left: &Function, right: &Function) { self.branch(&[left, right]); }
+            fn visit_quotient(&mut self, top: &Function, bottom: &Function) { self.branch(&[top, bottom]); }
+            fn visit_composed(&mut self, source: &Function, target: &Function) { self.branch(&[source, target]); }
+            fn visit_unary(&mut self, _unary: &UnaryFunction) { self.leaves += 1; }
+            fn visit_variable(&mut self, _name: &str) { self.leaves += 1; }
+            fn visit_clamp(&mut self, source: &Function, _lo: f64, _hi: f64) { self.branch(&[source]); }
+            fn visit_piecewise(&mut self, _threshold: f64, below: &Function, above: &Function) { self.branch(&[below, above]); }
+            fn visit_opaque(&mut self, _opaque: &OpaqueFunction) { self.leaves += 1; }
+            fn visit_power(&mut self, base: &Function, _power: i32) { self.branch(&[base]); }
+        }
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let f = UnaryFunction::Sin.new().of(x_sqr).sub(UnaryFunction::Const(1.0).new().neg());
+
+        let mut visitor = Leaves { leaves: 0, branches: 0 };
+        f.accept(&mut visitor);
+
+        assert_eq!(visitor.leaves, 4);
+        assert_eq!(visitor.leaves + visitor.branches, f.count_nodes());
+        assert_eq!(f.count_nodes(), 8);
+    }
+
+    #[test]
+    fn substitute_var_sin() {
+
+        let t = VariableFunction::new("t");
+        let t_sqr = Rc::clone(&t).mul(t);
+        let sin_of_t_sqr = UnaryFunction::Sin.new().substitute_var("x", &t_sqr);
+        assert_eq!(sin_of_t_sqr.to_string(), "sin(t*t)");
+
+        let mut env = HashMap::new();
+        env.insert(String::from("t"), 1.5);
+        assert_eq!(sin_of_t_sqr.eval_env(&env), 2.25f64.sin());
+    }
+
+    #[test]
+    fn implicit_circle() {
+
+        let x = VariableFunction::new("x");
+        let y = VariableFunction::new("y");
+        let x_sqr = Rc::clone(&x).mul(Rc::clone(&x));
+        let y_sqr = Rc::clone(&y).mul(Rc::clone(&y));
+        let circle = x_sqr.add(y_sqr).sub(UnaryFunction::Const(1.0).new());
+
+        let dy_dx = implicit_dy_dx(&circle);
+        let minus_x_over_y = x.neg().div(y);
+
+        let mut env = HashMap::new();
+        env.insert(String::from("x"), 0.6);
+        env.insert(String::from("y"), 0.8);
+        assert!((dy_dx.eval_env(&env) + 0.75).abs() < 1e-12);
+        assert!((dy_dx.eval_env(&env) - minus_x_over_y.eval_env(&env)).abs() < 1e-12);
+        assert_eq!(dy_dx.to_string_in("x"), "(-(2x)) / (2y)");
+    }
+
+    #[test]
+    fn eval_clamp() {
+
+        let clamped = ClampFunction::new(UnaryFunction::Id.new(), 0.0, 1.0);
+        assert_eq!(clamped.eval(&-1.0), 0.0);
+        assert_eq!(clamped.eval(&0.5), 0.5);
+        assert_eq!(clamped.eval(&2.0), 1.0);
+    }
+
+    #[test]
+    fn diff_clamp() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let clamped = ClampFunction::new(x_sqr, 0.0, 1.0).diff();
+        assert_eq!(clamped.eval(&0.5), 1.0);
+        assert_eq!(clamped.eval(&2.0), 0.0);
+    }
+
+    #[test]
+    fn period_trig() {
+
+        let two_pi = 2.0 * std::f64::consts::PI;
+        let three_x = UnaryFunction::Const(3.0).new().mul(UnaryFunction::Id.new());
+
+        assert!((UnaryFunction::Sin.new().period().unwrap() - two_pi).abs() < 1e-12);
+        assert!((UnaryFunction::Cos.new().of(three_x).period().unwrap() - two_pi / 3.0).abs() < 1e-12);
+        assert!(UnaryFunction::Exp.new().period().is_none());
+        assert!(UnaryFunction::Id.new().add(UnaryFunction::Sin.new()).period().is_none());
+    }
+
+    #[test]
+    fn simplify_identities() {
+
+        let zero = UnaryFunction::Const(0.0).new();
+        let one = UnaryFunction::Const(1.0).new();
+        let x_plus_zero = UnaryFunction::Id.new().add(zero);
+        let f = x_plus_zero.mul(one).add(UnaryFunction::Const(2.0).new().mul(UnaryFunction::Const(3.0).new()));
+        assert_eq!(f.simplify_fully().to_string(), "($) + (6)");
+    }
+
+    #[test]
+    fn nth_diff_simplified_inv() {
+
+        let inv = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new());
+        let naive = inv.nth_diff(4);
+        let simplified = inv.nth_diff_simplified(4);
+
+        assert!(simplified.count_nodes() * 4 < naive.count_nodes());
+        assert!((naive.eval(&1.5) - simplified.eval(&1.5)).abs() < 1e-9);
+        assert!((simplified.eval(&1.5) - 24.0 / 1.5f64.powi(5)).abs() < 1e-9);
+    }
+
+    #[cfg(feature = "rational")]
+    #[test]
+    fn eval_rational_inv() {
+
+        let inv = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new());
+        assert_eq!(inv.eval_rational(Ratio::new(1, 3)), Some(Ratio::new(3, 1)));
+        assert_eq!(UnaryFunction::Sin.new().eval_rational(Ratio::new(1, 3)), None);
+    }
+
+    #[test]
+    fn pretty_tree_product() {
+
+        let two_x = UnaryFunction::Id.new().mul(UnaryFunction::Const(2.0).new());
+        assert_eq!(two_x.pretty_tree(), "Product\n  Id\n  Const(2)");
+    }
+
+    #[test]
+    fn tan_half_angle_sin_cos() {
+
+        let sin_t = UnaryFunction::Sin.new().tan_half_angle_form();
+        let cos_t = UnaryFunction::Cos.new().of(UnaryFunction::Id.new()).tan_half_angle_form();
+
+        for &x in &[-1.0, 0.3, 2.0] {
+
+            let mut env = HashMap::new();
+            env.insert(String::from("t"), f64::tan(x / 2.0));
+
+            assert!((sin_t.eval_env(&env) - x.sin()).abs() < 1e-12);
+            assert!((cos_t.eval_env(&env) - x.cos()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn match_pattern_plus_zero() {
+
+        let sin_plus_zero = UnaryFunction::Sin.new().add(UnaryFunction::Const(0.0).new());
+        let pattern = Pattern::Sum(Box::new(Pattern::wild("a")), Box::new(Pattern::Const(0.0)));
+
+        let bindings = sin_plus_zero.match_pattern(&pattern).unwrap();
+        assert_eq!(bindings["a"].to_string(), "sin($)");
+        assert!(UnaryFunction::Sin.new().match_pattern(&pattern).is_none());
+    }
+
+    #[test]
+    fn match_pattern_repeated_wild() {
+
+        let pattern = Pattern::Product(Box::new(Pattern::wild("a")), Box::new(Pattern::wild("a")));
+        let sin_sqr = UnaryFunction::Sin.new().mul(UnaryFunction::Sin.new());
+        let sin_cos = UnaryFunction::Sin.new().mul(UnaryFunction::Cos.new());
+
+        assert!(sin_sqr.match_pattern(&pattern).is_some());
+        assert!(sin_cos.match_pattern(&pattern).is_none());
+    }
+
+    #[test]
+    fn rewrite_plus_zero() {
+
+        let rule = Rule {
+            lhs: Pattern::Sum(Box::new(Pattern::wild("a")), Box::new(Pattern::Const(0.0))),
+            rhs: Pattern::wild("a"),
+        };
+
+        let zero = UnaryFunction::Const(0.0).new();
+        let f = UnaryFunction::Id.new().add(Rc::clone(&zero)).add(zero);
+        assert_eq!(f.rewrite(&[rule]).to_string(), "$");
+
+        let unbound = Rule { lhs: Pattern::Const(0.0), rhs: Pattern::wild("b") };
+        let g = UnaryFunction::Sin.new().add(UnaryFunction::Const(0.0).new());
+        assert_eq!(g.rewrite(&[unbound]).to_string(), "(sin($)) + (0)");
+        assert!(Pattern::wild("b").instantiate(&HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn taylor_error_bound_exp() {
+
+        let bound = UnaryFunction::Exp.new().taylor_error_bound(0.0, 4, 1.0);
+        let taylor = 1.0 + 1.0 + 1.0 / 2.0 + 1.0 / 6.0 + 1.0 / 24.0;
+        let error = (1f64.exp() - taylor).abs();
+
+        assert!(bound > 0.0 && bound < 0.05);
+        assert!(bound >= error);
+    }
+
+    #[test]
+    fn closure_outlives_binding() {
+
+        let closure = {
+
+            let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+            x_sqr.as_closure()
+        };
+
+        assert_eq!(closure(3.0), 9.0);
+    }
+
+    #[test]
+    fn opaque_cube_in_sin() {
+
+        let cube = OpaqueFunction::with_diff("cube", |x: f64| x.powi(3), |x: f64| 3.0 * x * x);
+        let sin_of_cube = UnaryFunction::Sin.new().of(cube);
+
+        assert_eq!(sin_of_cube.to_string(), "sin(cube($))");
+        assert_eq!(sin_of_cube.eval(&1.2), 1.728f64.sin());
+        assert!((sin_of_cube.diff().eval(&1.2) - 1.728f64.cos() * 4.32).abs() < 1e-12);
+    }
+
+    #[test]
+    fn opaque_numeric_diff() {
+
+        let cube = OpaqueFunction::new("cube", |x: f64| x.powi(3));
+        assert!((cube.diff().eval(&2.0) - 12.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn eval_slice_sqr() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        assert_eq!(x_sqr.eval_slice(&[1.0, 2.0, 3.0]), vec![1.0, 4.0, 9.0]);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn eval_par_matches_slice() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let f = UnaryFunction::Sin.new().of(x_sqr).div(UnaryFunction::Exp.new()).add(ClampFunction::new(UnaryFunction::Id.new(), -1.0, 1.0));
+        let xs: Vec<f64> = (0..10000).map(|i| i as f64 / 1000.0 - 5.0).collect();
+
+        assert_eq!(f.eval_par(&xs), f.eval_slice(&xs));
+    }
+
+    #[test]
+    fn sample_uniform_sin() {
+
+        let two_pi = 2.0 * std::f64::consts::PI;
+        let samples = UnaryFunction::Sin.new().sample_uniform(0.0, two_pi, 8);
+        let half_root_two = 0.5f64.sqrt();
+        let expected = [0.0, half_root_two, 1.0, half_root_two, 0.0, -half_root_two, -1.0, -half_root_two];
+
+        assert_eq!(samples.len(), 8);
+
+        for (sample, value) in samples.iter().zip(&expected) {
+
+            assert!((sample - value).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn lipschitz_sin_id() {
+
+        assert!(UnaryFunction::Sin.new().lipschitz_constant(-3.0, 5.0, 1000) <= 1.0);
+        assert!(UnaryFunction::Sin.new().lipschitz_constant(-3.0, 5.0, 1000) > 0.99);
+        assert!((UnaryFunction::Id.new().lipschitz_constant(0.0, 1.0, 10) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fixed_point_cos() {
+
+        let fixed = UnaryFunction::Cos.new().fixed_point(1.0, 1000, 1e-12).unwrap();
+        assert!((fixed - 0.739085133).abs() < 1e-8);
+        assert!(UnaryFunction::Exp.new().fixed_point(1.0, 1000, 1e-12).is_none());
+    }
+
+    #[test]
+    fn round_constants_display() {
+
+        let c = UnaryFunction::Const(1.23456).new();
+        assert_eq!(c.round_constants(2).to_string(), "1.23");
+
+        let two_x = UnaryFunction::Const(2.0000000003).new().mul(UnaryFunction::Id.new());
+        assert_eq!(two_x.round_constants(3).to_string(), "2$");
+    }
+
+    #[test]
+    fn to_dot_sum() {
+
+        let dot = UnaryFunction::Id.new().add(UnaryFunction::Const(2.0).new()).to_dot();
+        let lines: Vec<&str> = dot.lines().collect();
+
+        assert_eq!(lines[0], "digraph {");
+        assert!(lines.contains(&"    n0 [label=\"+\"];"));
+        assert!(lines.contains(&"    n1 [label=\"x\"];"));
+        assert!(lines.contains(&"    n2 [label=\"2\"];"));
+        assert!(lines.contains(&"    n0 -> n1;"));
+        assert!(lines.contains(&"    n0 -> n2;"));
+    }
+
+    #[test]
+    fn as_affine_cases() {
+
+        let two_x_plus_three = UnaryFunction::Const(2.0).new().mul(UnaryFunction::Id.new()).add(UnaryFunction::Const(3.0).new());
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+
+        assert_eq!(two_x_plus_three.as_affine(), Some((2.0, 3.0)));
+        assert_eq!(UnaryFunction::Id.new().as_affine(), Some((1.0, 0.0)));
+        assert_eq!(x_sqr.as_affine(), None);
+    }
+
+    #[test]
+    fn eval_into_buffer() {
+
+        let mut out = [0.0; 3];
+        UnaryFunction::Id.new().eval_into(&[1.0, 2.0, 3.0], &mut out);
+        assert_eq!(out, [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn eval_into_length_mismatch() {
+
+        let mut out = [0.0; 2];
+        UnaryFunction::Id.new().eval_into(&[1.0, 2.0, 3.0], &mut out);
+    }
+
+    #[test]
+    fn normalize_signs_expanded_difference() {
+
+        let x_minus_sin = UnaryFunction::Id.new().sub(UnaryFunction::Sin.new());
+        let expanded = x_minus_sin.expand();
+
+        assert_eq!(expanded.to_string(), "($) + (-(sin($)))");
+        assert_eq!(expanded.normalize_signs().to_string(), "($) - (sin($))");
+        assert_eq!(UnaryFunction::Id.new().neg().neg().normalize_signs().to_string(), "$");
+    }
+
+    #[test]
+    fn prefix_round_trip() {
+
+        let sin = UnaryFunction::Sin.new();
+        assert_eq!(sin.to_prefix(), Some(vec![Token::Unary(UnaryFunction::Sin)]));
+        assert_eq!(eval_tokens(&sin.to_prefix().unwrap(), 1.0), Ok(sin.eval(&1.0)));
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let f = UnaryFunction::Log.new().of(x_sqr).sub(UnaryFunction::Cos.new().div(UnaryFunction::Exp.new()));
+        assert_eq!(eval_tokens(&f.to_prefix().unwrap(), 1.7), Ok(f.eval(&1.7)));
+    }
+
+    #[test]
+    fn prefix_rejects_malformed_streams() {
+
+        let opaque = OpaqueFunction::new("f", |x| x.abs());
+        assert_eq!(UnaryFunction::Sin.new().add(opaque).to_prefix(), None);
+
+        let sin = Token::Unary(UnaryFunction::Sin);
+        assert_eq!(eval_tokens(&[Token::Add, sin.clone()], 1.0), Err(TokenError::MissingOperand));
+        assert_eq!(eval_tokens(&[Token::Compose, sin.clone()], 1.0), Err(TokenError::MissingOperand));
+        assert_eq!(eval_tokens(&[], 1.0), Err(TokenError::MissingOperand));
+        assert_eq!(eval_tokens(&[sin.clone(), sin], 1.0), Err(TokenError::TrailingTokens(1)));
+        assert_eq!(eval_tokens(&[Token::Variable(String::from("y"))], 1.0), Err(TokenError::FreeVariable(String::from("y"))));
+    }
+
+    #[test]
+    fn memory_footprint_sharing() {
+
+        let build = || UnaryFunction::Sin.new().of(UnaryFunction::Id.new().mul(UnaryFunction::Id.new()));
+
+        let shared = build();
+        let shared_sum = Rc::clone(&shared).add(shared);
+        let cloned_sum = build().add(build());
+
+        assert!(shared_sum.memory_footprint() < cloned_sum.memory_footprint());
+        assert_eq!(shared_sum.count_nodes(), cloned_sum.count_nodes());
+    }
+
+    #[test]
+    fn eval_gamma() {
+
+        let gamma = UnaryFunction::Gamma.new();
+        assert!((gamma.eval(&5.0) - 24.0).abs() < 1e-9);
+        assert!((gamma.eval(&0.5) - std::f64::consts::PI.sqrt()).abs() < 1e-9);
+        assert_eq!(gamma.to_string(), "Γ($)");
+    }
+
+    #[test]
+    fn diff_gamma() {
+
+        // Γ'(1) = -γ, the Euler-Mascheroni constant
+        let euler_gamma = 0.577_215_664_901_532_9;
+        assert!((UnaryFunction::Gamma.new().diff().eval(&1.0) + euler_gamma).abs() < 1e-9);
+        assert!((UnaryFunction::Digamma.new().diff().eval(&1.0) - std::f64::consts::PI.powi(2) / 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn eval_erf() {
+
+        let erf = UnaryFunction::Erf.new();
+        assert_eq!(erf.eval(&0.0), 0.0);
+        assert!((erf.eval(&1.0) - 0.842_700_792_949_715).abs() < 1e-12);
+        assert!((erf.eval(&-4.0) + 0.999_999_984_582_742).abs() < 1e-12);
+        assert!((UnaryFunction::Erfc.new().eval(&1.0) - 0.157_299_207_050_285).abs() < 1e-12);
+    }
+
+    #[test]
+    fn diff_erf() {
+
+        let slope = UnaryFunction::Erf.new().diff().eval(&0.0);
+        assert!((slope - 2.0 / std::f64::consts::PI.sqrt()).abs() < 1e-15);
+        assert_eq!(UnaryFunction::Erf.new().to_string(), "erf($)");
+    }
+
+    #[test]
+    fn diff_unit_circle() {
+
+        let circle = VectorFunction::new(vec![UnaryFunction::Cos.new(), UnaryFunction::Sin.new()]);
+        assert_eq!(circle.eval_vec(0.0), vec![1.0, 0.0]);
+        assert_eq!(circle.diff().eval_vec(0.0), vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn curvature_line_parabola() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+
+        assert_eq!(UnaryFunction::Id.new().curvature(-3.0), 0.0);
+        assert_eq!(UnaryFunction::Id.new().curvature(5.0), 0.0);
+        assert_eq!(x_sqr.curvature(0.0), 2.0);
+    }
+
+    #[test]
+    fn secant_root_sqr() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let x_sqr_minus_two = x_sqr.sub(UnaryFunction::Const(2.0).new());
+        let root = x_sqr_minus_two.secant_root(1.0, 2.0, 100, 1e-12).unwrap();
+        assert!((root - 2f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bisect_root_cos() {
+
+        let root = UnaryFunction::Cos.new().bisect_root(1.0, 2.0, 1e-12).unwrap();
+        assert!((root - std::f64::consts::FRAC_PI_2).abs() < 1e-10);
+        assert!(UnaryFunction::Cos.new().bisect_root(2.0, 3.0, 1e-12).is_none());
+    }
+
+    #[test]
+    fn compose_by_reference() {
+
+        let sin = UnaryFunction::Sin.new();
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let exp = UnaryFunction::Exp.new();
+
+        let sin_of_sqr = sin.compose(&x_sqr);
+        let sin_of_exp = sin.compose(&exp);
+
+        assert_eq!(sin_of_sqr.eval(&2.0), 4f64.sin());
+        assert_eq!(sin_of_exp.eval(&1.0), 1f64.exp().sin());
+        assert_eq!(sin.eval(&3.0), 3f64.sin());
+    }
+
+    #[test]
+    fn free_functions_sqr() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        assert_eq!(evaluate(&x_sqr, 3.0), 9.0);
+        assert_eq!(evaluate(&differentiate(&x_sqr), 3.0), 6.0);
+    }
+
+    #[test]
+    fn drop_small_constants_term() {
+
+        let tiny_x = UnaryFunction::Const(1e-12).new().mul(UnaryFunction::Id.new());
+        let f = tiny_x.add(UnaryFunction::Id.new());
+        let dropped = f.drop_small_constants(1e-9);
+
+        assert_eq!(dropped.to_string(), "$");
+        assert_eq!(dropped.eval(&4.0), 4.0);
+    }
+
+    #[test]
+    fn numeric_diff_sin() {
+
+        assert!((UnaryFunction::Sin.new().numeric_diff(1.0) - 1f64.cos()).abs() < 1e-9);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn random_diff_matches_numeric() {
+
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x5eed);
+
+        for _ in 0..200 {
+
+            let f = random_function(4, &mut rng);
+            let derivative = f.diff();
+
+            for &x in &[-1.3, -0.4, 0.7, 1.9] {
+
+                let symbolic = derivative.eval(&x);
+                let numeric = f.numeric_diff(x);
+
+                // Skip points near singularities where the difference quotient is meaningless
+                if !symbolic.is_finite() || !numeric.is_finite() || symbolic.abs() > 1e4 {
+
+                    continue;
+                }
+
+                assert!((symbolic - numeric).abs() < 1e-4 * (1.0 + symbolic.abs()), "{}: {} vs {} at {}", f, symbolic, numeric, x);
+            }
+        }
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn random_expand_preserves_eval() {
+
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0xe8a2d);
+
+        for _ in 0..500 {
+
+            let f = random_function(4, &mut rng);
+            let terms = f.expand_vec();
+            let expanded = f.expand();
+
+            for &x in &[-1.3, -0.4, 0.7, 1.9] {
+
+                let value = f.eval(&x);
+                let magnitude: f64 = terms.iter().map(|term| term.eval(&x).abs()).sum();
+
+                if !value.is_finite() || !magnitude.is_finite() {
+
+                    continue;
+                }
+
+                assert!((expanded.eval(&x) - value).abs() <= 1e-9 * (1.0 + magnitude), "{}: {} vs {} at {}", f, expanded.eval(&x), value, x);
+            }
+        }
+    }
+
+    #[test]
+    fn diff_simplified_sqr() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        assert_eq!(x_sqr.diff().to_string(), "(1$) + (1$)");
+        assert_eq!(x_sqr.diff_simplified().to_string(), "2$");
+    }
+
+    #[test]
+    fn eval_env_slice_sum() {
+
+        let x_plus_y = VariableFunction::new("x").add(VariableFunction::new("y"));
+        let envs: Vec<HashMap<String, f64>> = vec![(1.0, 2.0), (3.0, -5.0)]
+            .into_iter()
+            .map(|(x, y)| vec![(String::from("x"), x), (String::from("y"), y)].into_iter().collect())
+            .collect();
+
+        assert_eq!(x_plus_y.eval_env_slice(&envs), vec![3.0, -2.0]);
+    }
+
+    #[test]
+    fn as_polynomial_cases() {
+
+        let x = || UnaryFunction::Id.new();
+        let x_minus_one_sqr = x().sub(UnaryFunction::Const(1.0).new()).mul(x().sub(UnaryFunction::Const(1.0).new()));
+
+        assert_eq!(x_minus_one_sqr.as_polynomial(), Some(vec![1.0, -2.0, 1.0]));
+        assert_eq!(x().sub(x()).as_polynomial(), Some(vec![]));
+        assert_eq!(UnaryFunction::Sin.new().as_polynomial(), None);
+    }
+
+    #[test]
+    fn leading_term_polynomial_rational() {
+
+        let x = || UnaryFunction::Id.new();
+        let x_cubed_plus_x = x().mul(x()).mul(x()).add(x());
+        let sqr_plus_one_over_x = x().mul(x()).add(UnaryFunction::Const(1.0).new()).div(x());
+        let one_over_sqr = UnaryFunction::Const(3.0).new().div(UnaryFunction::Const(2.0).new().mul(x()).mul(x()).add(x()));
+
+        assert_eq!(x_cubed_plus_x.leading_term_as_x_to_infinity().as_polynomial(), Some(vec![0.0, 0.0, 0.0, 1.0]));
+        assert_eq!(sqr_plus_one_over_x.leading_term_as_x_to_infinity().to_string(), "$");
+        assert_eq!(one_over_sqr.leading_term_as_x_to_infinity().to_string(), "(1.5) / ($*$)");
+        assert_eq!(UnaryFunction::Sin.new().leading_term_as_x_to_infinity().to_string(), "sin($)");
+    }
+
+    #[test]
+    fn eval_with_error_cancellation() {
+
+        let big = UnaryFunction::Const(1e10).new();
+        let cancelling = UnaryFunction::Id.new().add(Rc::clone(&big)).sub(big);
+
+        assert_eq!(UnaryFunction::Id.new().eval_with_error(1.0), (1.0, 0.0));
+
+        let (value, err) = cancelling.eval_with_error(1.0);
+        assert_eq!(value, 1.0);
+        assert!(err > 1e-7);
+
+        let one = || UnaryFunction::Const(1.0).new();
+        let (_, err) = one().div(Rc::clone(&cancelling).sub(UnaryFunction::Const(0.9999999).new())).eval_with_error(1.0);
+        assert_eq!(err, f64::INFINITY);
+
+        let (value, err) = one().div(cancelling.sub(one())).eval_with_error(1.0);
+        assert_eq!((value, err), (f64::INFINITY, f64::INFINITY));
+
+        let (value, err) = one().div(UnaryFunction::Const(4.0).new()).eval_with_error(1.0);
+        assert_eq!(value, 0.25);
+        assert!(err.is_finite() && err < 1e-16);
+
+        assert_eq!(IntPowFunction::new(UnaryFunction::Id.new(), 0).eval_with_error(0.0), (1.0, half_ulp(1.0)));
+    }
+
+    #[test]
+    fn display_coefficients() {
+
+        let two = || UnaryFunction::Const(2.0).new();
+
+        assert_eq!(two().mul(UnaryFunction::Id.new()).to_string().replace("$", "x"), "2x");
+        assert_eq!(UnaryFunction::Sin.new().mul(UnaryFunction::Const(3.0).new()).to_string().replace("$", "x"), "3sin(x)");
+        assert_eq!(UnaryFunction::Id.new().mul(UnaryFunction::Sin.new()).to_string().replace("$", "x"), "x*sin(x)");
+        assert_eq!(two().mul(UnaryFunction::Const(3.0).new().mul(UnaryFunction::Id.new())).to_string().replace("$", "x"), "2(3x)");
+        assert_eq!(two().mul(UnaryFunction::Id.new().add(two())).to_string().replace("$", "x"), "2((x) + (2))");
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let x_plus_two = UnaryFunction::Id.new().add(two());
+        assert_eq!(x_sqr.of(x_plus_two).to_string().replace("$", "x"), "((x) + (2))*((x) + (2))");
+
+        let doubled = || two().mul(UnaryFunction::Id.new());
+        assert_eq!(doubled().of(UnaryFunction::Const(3.0).new()).to_string(), "2(3)");
+        assert_eq!(doubled().of(UnaryFunction::Const(-3.0).new()).to_string(), "2(-3)");
+        assert_eq!(IntPowFunction::new(UnaryFunction::Id.new(), 2).of(UnaryFunction::Const(-3.0).new()).to_string(), "(-3)^2");
+        assert_eq!(UnaryFunction::Sin.new().of(UnaryFunction::Const(-3.0).new()).to_string(), "sin(-3)");
+    }
+
+    #[test]
+    fn factor_common_x() {
+
+        let x_sin = UnaryFunction::Id.new().mul(UnaryFunction::Sin.new());
+        let x_cos = UnaryFunction::Id.new().mul(UnaryFunction::Cos.new());
+        let f = x_sin.add(x_cos);
+        let factored = f.factor_common();
+
+        assert_eq!(factored.to_string(), "$*((sin($)) + (cos($)))");
+        assert!(factored.count_nodes() < f.count_nodes());
+        assert!((factored.eval(&0.7) - f.eval(&0.7)).abs() < 1e-12);
+        assert_eq!(UnaryFunction::Sin.new().add(UnaryFunction::Id.new()).factor_common().to_string(), "(sin($)) + ($)");
+    }
+
+    #[test]
+    fn combine_quotients_common_denominator() {
+
+        let x_over_cos = UnaryFunction::Id.new().div(UnaryFunction::Cos.new());
+        let one_over_cos = UnaryFunction::Const(1.0).new().div(UnaryFunction::Cos.new());
+        let f = x_over_cos.add(one_over_cos);
+        let combined = f.combine_quotients();
+
+        assert_eq!(combined.to_string(), "(($) + (1)) / (cos($))");
+        assert!((combined.eval(&0.4) - f.eval(&0.4)).abs() < 1e-12);
+
+        let one_over_x = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new());
+        let mixed = one_over_x.sub(UnaryFunction::Const(1.0).new().div(UnaryFunction::Exp.new())).combine_quotients();
+        assert!(matches!(Shape::of(&*mixed), Shape::Quotient(_, _)));
+        assert!((mixed.eval(&0.4) - (1.0 / 0.4 - 1.0 / 0.4f64.exp())).abs() < 1e-12);
+    }
+
+    #[test]
+    fn distribute_top_level_product() {
+
+        let sum = UnaryFunction::Sin.new().add(UnaryFunction::Cos.new());
+        let f = UnaryFunction::Id.new().mul(sum);
+        let distributed = f.distribute();
+
+        match Shape::of(&*distributed) {
+
+            Shape::Sum(left, right) => {
+
+                assert!(matches!(Shape::of(&*left), Shape::Product(_, _)));
+                assert!(matches!(Shape::of(&*right), Shape::Product(_, _)));
+            },
+
+            _ => panic!("expected a sum of products"),
+        }
+
+        for x in &[-1.5, 0.0, 0.7, 2.0] {
+
+            assert!((distributed.eval(x) - f.eval(x)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn simplify_trig_pythagorean_identity() {
+
+        let sin_sqr = UnaryFunction::Sin.new().mul(UnaryFunction::Sin.new());
+        let cos_sqr = UnaryFunction::Cos.new().mul(UnaryFunction::Cos.new());
+        let f = sin_sqr.add(cos_sqr).simplify_trig();
+
+        assert_eq!(f.to_string(), "1");
+        assert_eq!(f.eval(&0.3), 1.0);
+
+        let arg = UnaryFunction::Exp.new();
+        let sin_g = UnaryFunction::Sin.new().compose(&arg);
+        let cos_g = UnaryFunction::Cos.new().compose(&arg);
+        let g = Rc::clone(&cos_g).mul(cos_g).add(Rc::clone(&sin_g).mul(sin_g)).simplify_trig();
+        assert_eq!(g.to_string(), "1");
+    }
+
+    #[test]
+    fn diff_logarithmic_matches_diff() {
+
+        let x = UnaryFunction::Id.new();
+        let cube = Rc::clone(&x).mul(Rc::clone(&x)).mul(Rc::clone(&x));
+
+        for x in &[-1.5, 0.3, 2.0] {
+
+            assert!((cube.diff_logarithmic().eval(x) - cube.diff().eval(x)).abs() < 1e-9);
+        }
+
+        let many = (0..5).fold(Rc::clone(&x), |f, _| f.mul(Rc::clone(&x)));
+        assert!(many.diff_logarithmic().count_nodes() < many.diff().count_nodes());
+
+        let ratio = UnaryFunction::Sin.new().mul(UnaryFunction::Exp.new()).div(UnaryFunction::Cos.new());
+        assert!((ratio.diff_logarithmic().eval(&0.4) - ratio.diff().eval(&0.4)).abs() < 1e-9);
+
+        // Zeros of a factor would otherwise give 0 * inf
+        assert_eq!(cube.diff_logarithmic().eval(&0.0), 0.0);
+        assert_eq!(Rc::clone(&x).mul(UnaryFunction::Exp.new()).diff_logarithmic().eval(&0.0), 1.0);
+
+        let scaled = UnaryFunction::Const(3.0).new().mul(Rc::clone(&x).neg()).div(UnaryFunction::Const(2.0).new().mul(UnaryFunction::Exp.new()));
+        assert!((scaled.diff_logarithmic().eval(&0.7) - scaled.diff().eval(&0.7)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn eval_with_derivatives_2_jets() {
+
+        let x = UnaryFunction::Id.new();
+        let cube = Rc::clone(&x).mul(Rc::clone(&x)).mul(x);
+
+        assert_eq!(cube.eval_with_derivatives_2(2.0), (8.0, 12.0, 12.0));
+
+        let f = UnaryFunction::Sin.new().of(UnaryFunction::Exp.new()).div(UnaryFunction::Cos.new());
+        let (value, first, second) = f.eval_with_derivatives_2(0.3);
+
+        assert!((value - f.eval(&0.3)).abs() < 1e-12);
+        assert!((first - f.diff().eval(&0.3)).abs() < 1e-9);
+        assert!((second - f.nth_diff(2).eval(&0.3)).abs() < 1e-9);
+
+        assert_eq!(IntPowFunction::new(UnaryFunction::Id.new(), 0).eval_with_derivatives_2(0.0), (1.0, 0.0, 0.0));
+        assert_eq!(IntPowFunction::new(UnaryFunction::Id.new(), 1).eval_with_derivatives_2(0.0), (0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn try_div_rejects_literal_zero() {
+
+        let id = UnaryFunction::Id.new();
+
+        assert_eq!(Rc::clone(&id).try_div(Function::zero()).err(), Some(BuildError::DivisionByZero));
+
+        let half = Rc::clone(&id).try_div(Function::constant(2.0)).expect("2 is non-zero");
+        assert_eq!(half.eval(&3.0), 1.5);
+    }
+
+    #[test]
+    fn replace_ptr_matches_by_pointer() {
+
+        let shared = UnaryFunction::Sin.new();
+        let lookalike = UnaryFunction::Sin.new();
+        let untouched = UnaryFunction::Exp.new();
+
+        let f = Rc::clone(&shared).add(lookalike).mul(Rc::clone(&untouched).add(Rc::clone(&shared)));
+        let replaced = f.replace_ptr(&shared, &UnaryFunction::Cos.new());
+
+        assert_eq!(replaced.to_string(), "((cos($)) + (sin($)))*((exp($)) + (cos($)))");
+
+        match (Shape::of(&*f), Shape::of(&*replaced)) {
+
+            (Shape::Product(_, before), Shape::Product(_, after)) => match (Shape::of(&*before), Shape::of(&*after)) {
+
+                (Shape::Sum(before, _), Shape::Sum(after, _)) => assert!(Rc::ptr_eq(&before, &after)),
+                _ => panic!("expected sums"),
+            },
+
+            _ => panic!("expected products"),
+        }
+    }
+
+    #[test]
+    fn operation_counts_by_category() {
+
+        let f = UnaryFunction::Sin.new().mul(UnaryFunction::Id.new()).add(UnaryFunction::Const(1.0).new());
+
+        assert_eq!(f.operation_counts(), OpCounts { additions: 1, multiplications: 1, divisions: 0, transcendentals: 1 });
+    }
+
+    #[test]
+    fn is_polynomial_in_named_variable() {
+
+        let x = VariableFunction::new("x");
+        let a = VariableFunction::new("a");
+        let b = VariableFunction::new("b");
+
+        let f = a.mul(Rc::clone(&x)).mul(Rc::clone(&x)).add(b.mul(x));
+
+        assert!(f.is_polynomial_in("x"));
+        assert!(f.is_polynomial_in("a"));
+        assert!(!UnaryFunction::Sin.new().is_polynomial_in("x"));
+        assert!(UnaryFunction::Sin.new().is_polynomial_in("a"));
+        assert!(!UnaryFunction::Id.new().div(UnaryFunction::Id.new()).is_polynomial_in("x"));
+    }
+
+    #[test]
+    fn parse_reader_from_bytes() {
+
+        let f = parse_reader(std::io::Cursor::new(&b"x + 1"[..])).expect("valid expression");
+
+        assert_eq!(f.to_string(), "($) + (1)");
+        assert_eq!(f.eval(&2.0), 3.0);
+
+        let g = parse_reader("3sin(2$) / Γ(x)".as_bytes()).expect("valid expression");
+        assert!((g.eval(&1.5) - 3.0 * 3.0f64.sin() / gamma(1.5)).abs() < 1e-12);
+
+        assert_eq!(parse("sin(x").err(), Some(ParseError::UnexpectedEnd));
+        assert_eq!(parse("tan(x)").err(), Some(ParseError::UnknownFunction(String::from("tan"))));
+    }
+
+    #[test]
+    fn to_postscript_polyline() {
+
+        let ps = UnaryFunction::Sin.new().to_postscript(0.0, 6.0, 25, 200.0, 100.0);
+
+        assert!(ps.starts_with("%!PS"));
+        assert_eq!(ps.matches("moveto").count(), 1);
+        assert_eq!(ps.matches("lineto").count(), 24);
+
+        let broken = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new()).to_postscript(-1.0, 1.0, 3, 10.0, 10.0);
+        assert_eq!(broken.matches("moveto").count(), 2);
+        assert_eq!(broken.matches("lineto").count(), 0);
+    }
+
+    #[test]
+    fn to_svg_path_polyline() {
+
+        let svg = UnaryFunction::Sin.new().to_svg_path(0.0, 2.0 * std::f64::consts::PI, 50, 400, 200);
+
+        assert!(svg.starts_with("<svg"));
+        assert_eq!(svg.matches('M').count(), 1);
+        assert_eq!(svg.matches('L').count(), 49);
+
+        let broken = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new()).to_svg_path(-1.0, 1.0, 3, 10, 10);
+        assert_eq!(broken.matches('M').count(), 2);
+    }
+
+    #[test]
+    fn dominant_frequency_of_sin() {
+
+        let f = UnaryFunction::Sin.new().of(UnaryFunction::Const(3.0).new().mul(UnaryFunction::Id.new()));
+        let g = Rc::clone(&f).add(UnaryFunction::Const(0.2).new().mul(UnaryFunction::Cos.new()));
+
+        assert!((f.dominant_frequency(2.0 * std::f64::consts::PI, 64) - 3.0).abs() < 1e-9);
+        assert!((g.dominant_frequency(2.0 * std::f64::consts::PI, 64) - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn memo_function_caches_by_input() {
+
+        let memo = Rc::new(MemoFunction::wrap(UnaryFunction::Gamma.new().mul(UnaryFunction::Erf.new())));
+
+        let first = memo.eval(&1.7);
+        assert_eq!(memo.cache_len(), 1);
+        assert_eq!(memo.eval(&1.7), first);
+        assert_eq!(memo.cache_len(), 1);
+
+        memo.eval(&2.5);
+        assert_eq!(memo.cache_len(), 2);
+
+        let derivative = memo.diff();
+        assert!((derivative.eval(&1.7) - memo.inner.diff().eval(&1.7)).abs() < 1e-12);
+        assert_eq!((memo as Function).count_nodes(), 3);
+    }
+
+    #[test]
+    fn named_function_displays_name() {
+
+        let inner = UnaryFunction::Sin.new().mul(UnaryFunction::Exp.new());
+        let named = NamedFunction::wrap("g", Rc::clone(&inner));
+
+        assert_eq!(named.to_string(), "g(x)");
+        assert_eq!(named.eval(&0.7), inner.eval(&0.7));
+        assert_eq!(named.diff().eval(&0.7), inner.diff().eval(&0.7));
+        assert!(Rc::ptr_eq(&named.unfold(), &inner));
+
+        let composed = UnaryFunction::Log.new().of(inner.wrap_with_name("g"));
+        assert_eq!(composed.to_string(), "ln(g(x))");
+        assert_eq!(inner.wrap_with_name("g").of(VariableFunction::new("y")).to_string(), "g(y)");
+        assert_eq!(inner.to_string_in("t"), "sin(t)*exp(t)");
+
+        let simplified = composed.add(UnaryFunction::Const(0.0).new()).simplify();
+        assert_eq!(simplified.to_string(), "ln(g(x))");
+        assert!(matches!(Shape::of(&*inner.wrap_with_name("g").to_function()), Shape::Named(ref name, _) if name == "g"));
+        assert!(simplified.structurally_equals(&*UnaryFunction::Log.new().of(Rc::clone(&inner))));
+    }
+
+    #[test]
+    fn primitives_used_lists_intrinsics() {
+
+        let f = UnaryFunction::Sin.new().add(UnaryFunction::Exp.new());
+        let polynomial = UnaryFunction::Id.new().mul(UnaryFunction::Id.new()).add(UnaryFunction::Const(1.0).new());
+
+        assert_eq!(f.primitives_used(), ["sin", "exp"].iter().cloned().collect());
+        assert!(polynomial.primitives_used().is_empty());
+    }
+
+    #[test]
+    fn try_diff_rejects_missing_derivatives() {
+
+        let abs = OpaqueFunction::new("abs", f64::abs);
+
+        assert_eq!(abs.try_diff().err(), Some(DiffError::NoDerivative(String::from("abs"))));
+        assert_eq!(UnaryFunction::Sin.new().mul(abs).try_diff().err(), Some(DiffError::NoDerivative(String::from("abs"))));
+        assert!(UnaryFunction::Sin.new().try_diff().is_ok());
+
+        let smooth = OpaqueFunction::with_diff("cube", |x| x * x * x, |x| 3.0 * x * x);
+        assert!(smooth.try_diff().is_ok());
+    }
+
+    #[test]
+    fn tabulate_aligned_rows() {
+
+        let table = UnaryFunction::Id.new().tabulate(0.0, 1.0, 4);
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0].split_whitespace().collect::<Vec<_>>(), vec!["x", "f(x)", "f'(x)"]);
+        assert_eq!(lines[2].split_whitespace().collect::<Vec<_>>(), vec!["0.250000", "0.250000", "1.000000"]);
+        assert!(lines.iter().all(|line| line.len() == lines[0].len()));
+    }
+
+    #[cfg(feature = "units")]
+    #[test]
+    fn check_dimensions_of_transcendental_arguments() {
+
+        let length = UnitFunction::new(VariableFunction::new("l"), Unit::base("length"));
+        let wave_number = UnitFunction::new(VariableFunction::new("k"), Unit::dimensionless().per(&Unit::base("length")));
+
+        let bad = UnaryFunction::Sin.new().of(Rc::clone(&length));
+        assert_eq!(bad.check_dimensions(), Err(UnitError::NotDimensionless(String::from("sin"), Unit::base("length"))));
+        assert_eq!(bad.simplify().check_dimensions(), bad.check_dimensions());
+        assert_eq!(bad.to_function().check_dimensions(), bad.check_dimensions());
+        assert_eq!(bad.substitute_var("l", &VariableFunction::new("m")).check_dimensions(), bad.check_dimensions());
+
+        let good = UnaryFunction::Sin.new().of(wave_number.mul(Rc::clone(&length)));
+        assert_eq!(good.check_dimensions(), Ok(()));
+
+        let mismatch = Rc::clone(&length).add(UnaryFunction::Exp.new());
+        assert!(matches!(mismatch.check_dimensions(), Err(UnitError::Mismatch(_, _))));
+    }
+
+    #[test]
+    fn simplify_powers_collects_factors() {
+
+        let x = UnaryFunction::Id.new();
+        let cube = Rc::clone(&x).mul(Rc::clone(&x)).mul(Rc::clone(&x));
+        let simplified = cube.simplify_powers();
+
+        assert_eq!(simplified.to_string().replace("$", "x"), "x^3");
+        assert_eq!(simplified.eval(&1.7), cube.eval(&1.7));
+
+        let mixed = IntPowFunction::new(Rc::clone(&x), 2).mul(UnaryFunction::Sin.new()).mul(x).simplify_powers();
+        assert_eq!(mixed.to_string(), "$^3*sin($)");
+        assert!((mixed.diff().eval(&0.8) - (3.0 * 0.64 * 0.8f64.sin() + 0.512 * 0.8f64.cos())).abs() < 1e-12);
+
+        let parsed = parse("2x^3 - sin(x)^-2").expect("valid expression");
+        assert!((parsed.eval(&0.5) - (0.25 - 0.5f64.sin().powi(-2))).abs() < 1e-12);
+        assert_eq!(parse(&mixed.to_string()).expect("valid expression").to_string(), mixed.to_string());
+    }
+
+    #[test]
+    fn fast_eval_uses_horner_on_inner() {
+
+        use std::cell::Cell;
+
+        let u_sqr_plus_u = IntPowFunction::new(UnaryFunction::Id.new(), 2).add(UnaryFunction::Id.new());
+        let composed = Rc::clone(&u_sqr_plus_u).of(UnaryFunction::Sin.new());
+        let fast = composed.fast_eval();
+
+        for x in &[-2.0, 0.3, 1.1] {
+
+            assert!((fast(*x) - composed.eval(x)).abs() < 1e-12);
+        }
+
+        let calls = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&calls);
+        let sin = OpaqueFunction::new("sin", move |x: f64| { counter.set(counter.get() + 1); x.sin() });
+        let spelled_out = Rc::clone(&sin).mul(Rc::clone(&sin)).add(sin);
+
+        let naive = spelled_out.eval(&0.7);
+        assert_eq!(calls.get(), 3);
+
+        let fast = spelled_out.fast_eval();
+        assert!((fast(0.7) - naive).abs() < 1e-12);
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn total_derivative_related_rates() {
+
+        let f = VariableFunction::new("x").mul(VariableFunction::new("y"));
+
+        let mut rates = HashMap::new();
+        rates.insert(String::from("x"), UnaryFunction::Const(2.0).new());
+        rates.insert(String::from("y"), UnaryFunction::Const(3.0).new());
+
+        let df_dt = f.total_derivative(&rates);
+        assert_eq!(df_dt.to_string().replace("$", "x"), "(2y) + (3x)");
+
+        let env: HashMap<String, f64> = [(String::from("x"), 5.0), (String::from("y"), 7.0)].iter().cloned().collect();
+        assert_eq!(df_dt.eval_env(&env), 2.0 * 7.0 + 3.0 * 5.0);
+    }
+
+    #[test]
+    fn analytic_inverse_of_primitives() {
+
+        let exp = UnaryFunction::Exp.new();
+        let ln = exp.analytic_inverse().expect("exp is invertible");
+
+        for x in &[-1.0, 0.5, 3.0] {
+
+            assert!((ln.compose(&exp).eval(x) - x).abs() < 1e-12);
+        }
+
+        let affine = UnaryFunction::Const(2.0).new().mul(UnaryFunction::Id.new()).add(UnaryFunction::Const(1.0).new());
+        assert!((affine.analytic_inverse().expect("affine is invertible").eval(&5.0) - 2.0).abs() < 1e-12);
+
+        let shifted = UnaryFunction::Const(3.0).new().mul(UnaryFunction::Exp.new()).sub(UnaryFunction::Const(1.0).new());
+        let inverse = shifted.analytic_inverse().expect("scaled exp is invertible");
+        assert!((inverse.eval(&shifted.eval(&0.4)) - 0.4).abs() < 1e-12);
+
+        assert!(UnaryFunction::Id.new().mul(UnaryFunction::Id.new()).analytic_inverse().is_none());
+    }
+
+    #[test]
+    fn eval_slice_interpolated_gaps() {
+
+        let f = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new());
+
+        assert_eq!(f.eval_slice_interpolated(&[-1.0, 0.0, 2.0]), vec![Some(-1.0), None, Some(0.5)]);
+    }
+
+    #[test]
+    fn jacobian_of_vector_function() {
+
+        let x = VariableFunction::new("x");
+        let y = VariableFunction::new("y");
+        let f = VectorFunction::new(vec![Rc::clone(&x).mul(Rc::clone(&y)), x.add(y)]);
+
+        let jacobian = f.jacobian(&["x", "y"]);
+        let env: HashMap<String, f64> = [(String::from("x"), 2.0), (String::from("y"), 5.0)].iter().cloned().collect();
+
+        let values: Vec<Vec<f64>> = jacobian.iter().map(|row| row.iter().map(|entry| entry.eval_env(&env)).collect()).collect();
+        assert_eq!(values, vec![vec![5.0, 2.0], vec![1.0, 1.0]]);
+
+        assert_eq!(jacobian[0][0].to_string(), "y");
+        assert_eq!(jacobian[1][1].to_string(), "1");
+    }
+
+    #[test]
+    fn solve_system_circle_line() {
+
+        let x = VariableFunction::new("x");
+        let y = VariableFunction::new("y");
+
+        let circle = Rc::clone(&x).mul(Rc::clone(&x)).add(Rc::clone(&y).mul(Rc::clone(&y))).sub(UnaryFunction::Const(1.0).new());
+        let system = VectorFunction::new(vec![circle, Rc::clone(&x).sub(Rc::clone(&y))]);
+
+        assert_eq!(system.variables(), vec!["x", "y"]);
+
+        let root = solve_system(&system, &[0.7, 0.7], 50, 1e-12).expect("should converge");
+        assert!((root[0] - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-9);
+        assert!((root[1] - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-9);
+
+        // ln(-1) is NaN, which has to give up rather than panic while pivoting
+        let x_ln_x = Rc::clone(&x).mul(UnaryFunction::Log.new().of(Rc::clone(&x)));
+        let nan_system = VectorFunction::new(vec![x_ln_x.add(Rc::clone(&y)), x.sub(y)]);
+        assert_eq!(solve_system(&nan_system, &[-1.0, 0.5], 10, 1e-12), None);
+        assert!(UnaryFunction::Const(f64::NAN).new().mul(UnaryFunction::Sin.new()).pade(0.0, 1, 2).is_none());
+    }
+
+    #[test]
+    fn pade_beats_taylor_for_exp() {
+
+        let exp = UnaryFunction::Exp.new();
+        let pade = exp.pade(0.0, 1, 1).expect("exp has a [1/1] approximant");
+
+        assert_eq!(pade.to_string(), "((1) + (0.5$)) / ((1) + (-0.5$))");
+
+        // At x = 1 itself the [1/1] approximant (3) is still further from e than 2.5 is,
+        // the advantage shows closer in and at the next order
+        let taylor = PolynomialFunction::new(exp.taylor_coefficients(0.0, 2));
+        assert!((pade.eval(&0.5) - 0.5f64.exp()).abs() < (taylor.eval(&0.5) - 0.5f64.exp()).abs());
+
+        let pade = exp.pade(0.0, 2, 2).expect("exp has a [2/2] approximant");
+        let taylor = PolynomialFunction::new(exp.taylor_coefficients(0.0, 4));
+        assert!((pade.eval(&1.0) - 1f64.exp()).abs() < (taylor.eval(&1.0) - 1f64.exp()).abs());
+
+        let shifted = exp.pade(1.0, 2, 2).expect("exp has a [2/2] approximant");
+        assert!((shifted.eval(&1.3) - 1.3f64.exp()).abs() < 1e-4);
     }
 
-    fn expand_vec(&self) -> Vec<Function> {
+    #[test]
+    fn eval_accurate_compensates() {
 
-        // TODO: Cancel inverses
-        vec![Rc::clone(&self.source).of(Rc::clone(&self.target))]
+        let mut terms = vec![UnaryFunction::Const(1e16).new()];
+        terms.extend((0..10).map(|_| UnaryFunction::Id.new()));
+        terms.push(UnaryFunction::Const(-1e16).new());
+
+        let f = SumFunction::from_many(&terms);
+
+        assert!((f.eval(&1.0) - 10.0).abs() > 1.0);
+        assert_eq!(f.eval_accurate(1.0), 10.0);
+        assert_eq!(UnaryFunction::Sin.new().sub(UnaryFunction::Cos.new()).eval_accurate(0.3), 0.3f64.sin() - 0.3f64.cos());
     }
-}
 
-impl fmt::Display for ComposedFunction {
+    #[test]
+    fn compile_matches_eval() {
 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let x = UnaryFunction::Id.new();
+        let f = UnaryFunction::Sin.new().of(Rc::clone(&x).mul(UnaryFunction::Exp.new()))
+            .div(IntPowFunction::new(UnaryFunction::Cos.new(), 2))
+            .sub(ClampFunction::new(x, -0.5, 0.5))
+            .add(OpaqueFunction::new("cube", |x| x * x * x));
 
-        let source_of_x = self.source.to_string();
-        let target_of_x = self.target.to_string();
+        let compiled = f.compile();
+
+        for i in 0..1000 {
 
-        write!(f, "{}", source_of_x.replace("$", &target_of_x))
+            let x = -1.0 + 2.0 * i as f64 / 1000.0;
+
+            assert_eq!(compiled.eval(x), f.eval(&x));
+        }
     }
-}
 
-#[derive(Copy, Clone)]
-pub enum UnaryFunction {
+    #[test]
+    fn coefficient_of_power() {
 
-    Const(f64),
-    Id,
-    Sin,
-    Cos,
-    Exp,
-    Log,
-}
+        let f = parse("3x^2 + x + 5").expect("valid expression");
 
-impl UnaryFunction {
+        assert_eq!(f.coefficient_of(2), Some(3.0));
+        assert_eq!(f.coefficient_of(0), Some(5.0));
+        assert_eq!(f.coefficient_of(7), Some(0.0));
+        assert_eq!(UnaryFunction::Sin.new().coefficient_of(1), None);
+    }
 
-    pub fn new(self) -> Function {
+    #[test]
+    fn canonicalize_affine_arguments_leading_coefficient() {
 
-        Rc::new(self)
+        let x = UnaryFunction::Id.new();
+        let f = UnaryFunction::Cos.new().of(Rc::clone(&x).add(Rc::clone(&x)));
+
+        assert_eq!(f.canonicalize_affine_arguments().to_string(), "cos(2$)");
+
+        let g = UnaryFunction::Sin.new().of(Rc::clone(&x).add(x).add(UnaryFunction::Const(1.0).new()));
+        let canonical = g.canonicalize_affine_arguments();
+
+        assert_eq!(canonical.to_string(), "sin((2$) + (1))");
+        assert_eq!(canonical.eval(&0.3), g.eval(&0.3));
     }
-}
 
-impl FunctionTrait for UnaryFunction {
+    #[test]
+    fn directional_derivative_of_paraboloid() {
 
-    fn eval(&self, x: &f64) -> f64 {
+        let x = VariableFunction::new("x");
+        let y = VariableFunction::new("y");
+        let f = Rc::clone(&x).mul(x).add(Rc::clone(&y).mul(y));
 
-        match self {
+        let along_x = f.directional_derivative(&["x", "y"], &[1.0, 0.0]);
+        assert_eq!(along_x.to_string().replace("$", "x"), "2x");
 
-            UnaryFunction::Const(c) => *c,
-            UnaryFunction::Id => *x,
-            UnaryFunction::Sin => x.sin(),
-            UnaryFunction::Cos => x.cos(),
-            UnaryFunction::Exp => x.exp(),
-            UnaryFunction::Log => x.ln(),
+        let diagonal = f.directional_derivative(&["x", "y"], &[std::f64::consts::FRAC_1_SQRT_2, std::f64::consts::FRAC_1_SQRT_2]);
+        let env: HashMap<String, f64> = [(String::from("x"), 1.0), (String::from("y"), 3.0)].iter().cloned().collect();
+
+        assert!((diagonal.eval_env(&env) - std::f64::consts::SQRT_2 * (1.0 + 3.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn to_wasm_func_opcodes() {
+
+        let f = UnaryFunction::Id.new().add(UnaryFunction::Const(1.0).new());
+
+        let mut expected = vec![0x00, 0x20, 0x00, 0x44];
+        expected.extend_from_slice(&1.0f64.to_le_bytes());
+        expected.extend_from_slice(&[0xa0, 0x0b]);
+
+        assert_eq!(f.to_wasm_func(), Some(expected));
+
+        let g = UnaryFunction::Exp.new().of(UnaryFunction::Sin.new());
+        assert_eq!(g.to_wasm_func(), Some(vec![0x01, 0x01, 0x7c, 0x20, 0x00, 0x10, 0x00, 0x21, 0x01, 0x20, 0x01, 0x10, 0x02, 0x0b]));
+
+        let free = UnaryFunction::Id.new().add(VariableFunction::new("y"));
+        assert_eq!(free.to_wasm_func(), None);
+
+        let opaque = UnaryFunction::Exp.new().of(OpaqueFunction::new("f", |x| x.abs()));
+        assert_eq!(opaque.to_wasm_func(), None);
+    }
+
+    #[test]
+    fn extrema_sqr() {
+
+        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
+        let (min_x, min_y) = x_sqr.minimize(-1.0, 2.0, 10);
+        assert!(min_x.abs() < 1e-9 && min_y.abs() < 1e-12);
+        assert_eq!(x_sqr.maximize(-1.0, 2.0, 10), (2.0, 4.0));
+    }
+
+    #[test]
+    fn simplify_negations_difference() {
+
+        let original = UnaryFunction::Id.new().sub(UnaryFunction::Sin.new()).neg();
+        let simplified = original.simplify_negations();
+        assert_eq!(format!("{}", simplified), "(sin($)) - ($)");
+
+        for x in [-2.0, 0.3, 1.7] {
+
+            assert_eq!(simplified.eval(&x), original.eval(&x));
         }
+
+        let doubled = UnaryFunction::Exp.new().neg().mul(UnaryFunction::Cos.new().neg()).neg();
+        assert_eq!(format!("{}", doubled.simplify_negations()), "-(exp($)*cos($))");
     }
 
-    fn diff(&self) -> Function {
+    #[test]
+    fn eval_trace_sinc() {
 
-        match self {
-            
-            UnaryFunction::Const(_) => UnaryFunction::Const(0.0).new(),
-            UnaryFunction::Id => UnaryFunction::Const(1.0).new(),
-            UnaryFunction::Sin => UnaryFunction::Cos.new(),
-            UnaryFunction::Cos => UnaryFunction::Sin.new().neg(),
-            UnaryFunction::Exp => UnaryFunction::Exp.new(),
-            UnaryFunction::Log => UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new()),
+        let sinc = UnaryFunction::Sin.new().div(UnaryFunction::Id.new());
+        let (value, steps) = sinc.eval_trace(2.0);
+        let sin_2 = 2f64.sin();
+
+        assert_eq!(value, sin_2 / 2.0);
+        assert_eq!(steps, vec![format!("sin(2.0) = {:?}", sin_2), format!("{:?} / 2.0 = {:?}", sin_2, sin_2 / 2.0)]);
+    }
+
+    #[test]
+    fn bind_constants_affine() {
+
+        let values = HashMap::from([(String::from("a"), 2.0), (String::from("b"), 3.0)]);
+        let bound = parse("a*x + b").expect("valid expression").bind_constants(&values);
+
+        for x in [-1.5, 0.0, 4.0] {
+
+            assert_eq!(bound.eval(&x), 2.0 * x + 3.0);
         }
     }
 
-    fn expand_vec(&self) -> Vec<Function> {
+    #[test]
+    fn monic_quadratic() {
 
-        vec![self.new()]
+        let original = parse("2x^2 + 4x + 2").expect("valid expression");
+        let monic = original.monic().unwrap();
+        assert_eq!(monic.as_polynomial(), Some(vec![1.0, 2.0, 1.0]));
+
+        for x in [-3.0, 0.5, 2.0] {
+
+            assert_eq!(original.eval(&x), 2.0 * monic.eval(&x));
+        }
+
+        assert!(UnaryFunction::Sin.new().monic().is_none());
+        assert!(UnaryFunction::Const(0.0).new().monic().is_none());
     }
-}
 
-impl fmt::Display for UnaryFunction {
+    #[test]
+    fn from_taylor_quadratic() {
 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let series = from_taylor(&[1.0, 1.0, 0.5], 0.0);
+        let derivative = series.diff();
 
-        let plain = match self {
+        for x in [-2.0, 0.0, 0.5, 3.0] {
 
-            UnaryFunction::Const(c) => c.to_string(),
-            UnaryFunction::Id => String::from("$"),
-            UnaryFunction::Sin => String::from("sin($)"),
-            UnaryFunction::Cos => String::from("cos($)"),
-            UnaryFunction::Exp => String::from("exp($)"),
-            UnaryFunction::Log => String::from("ln($)"),
-        };
+            assert_eq!(series.eval(&x), 1.0 + x + 0.5 * x * x);
+            assert_eq!(derivative.eval(&x), 1.0 + x);
+        }
 
-        write!(f, "{}", plain)
+        let shifted = from_taylor(&[1.0, 1.0, 0.5], 2.0);
+        assert_eq!(shifted.eval(&3.0), 2.5);
+        assert_eq!(shifted.taylor_coefficients(2.0, 2), vec![1.0, 1.0, 0.5]);
     }
-}
 
-#[cfg(test)]
-mod test {
+    #[test]
+    fn sample_chebyshev_clusters() {
 
-    use super::*;
+        let samples = UnaryFunction::Exp.new().sample_chebyshev(0.0, 2.0, 11);
+        let xs: Vec<f64> = samples.iter().map(|(x, _)| *x).collect();
+
+        assert!((xs[0] - 0.0).abs() < 1e-12 && (xs[10] - 2.0).abs() < 1e-12 && (xs[5] - 1.0).abs() < 1e-12);
+        assert!(samples.iter().all(|(x, y)| *y == x.exp()));
+
+        // Gaps shrink towards either end
+        let gaps: Vec<f64> = xs.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(gaps[0] < gaps[2] && gaps[2] < gaps[4]);
+        assert!(gaps[9] < gaps[7] && gaps[7] < gaps[5]);
+    }
 
     #[test]
-    fn eval_const() {
+    fn interpolate_sqr() {
 
-        let two = UnaryFunction::Const(2.0).new();
-        assert_eq!(two.eval(&1.0), 2.0);
+        let points = [(-1.0, 1.0), (0.5, 0.25), (2.0, 4.0)];
+        let interpolated = interpolate(&points);
+        let derivative = interpolated.diff();
+
+        for (x, y) in points {
+
+            assert!((interpolated.eval(&x) - y).abs() < 1e-12);
+        }
+
+        for x in [-3.0, 0.0, 1.5] {
+
+            assert!((interpolated.eval(&x) - x * x).abs() < 1e-12);
+            assert!((derivative.eval(&x) - 2.0 * x).abs() < 1e-12);
+        }
     }
 
     #[test]
-    fn eval_id() {
+    fn piecewise_c1_joins() {
 
-        let id = UnaryFunction::Id.new();
-        assert_eq!(id.eval(&3.0), 3.0);
+        let relu = PiecewiseFunction::wrap(0.0, UnaryFunction::Const(0.0).new(), UnaryFunction::Id.new());
+        assert_eq!(relu.below.eval(&0.0), relu.above.eval(&0.0));
+        assert!(!relu.is_c1_continuous(1e-12));
+
+        // x^2 meets its tangent line 2x - 1 at x = 1
+        let tangent = UnaryFunction::Const(2.0).new().mul(UnaryFunction::Id.new()).sub(UnaryFunction::Const(1.0).new());
+        let join = PiecewiseFunction::wrap(1.0, IntPowFunction::new(UnaryFunction::Id.new(), 2), tangent);
+        assert!(join.is_c1_continuous(1e-12));
     }
 
     #[test]
-    fn eval_sin() {
+    fn reflect_exp() {
 
-        let sin = UnaryFunction::Sin.new();
-        assert_eq!(sin.eval(&4.0), 4f64.sin());
+        let exp = UnaryFunction::Exp.new();
+        let (reflected_x, reflected_y) = (exp.reflect_x(), exp.reflect_y());
+
+        for x in [-2.0, 0.0, 1.5] {
+
+            assert_eq!(reflected_x.eval(&x), (-x).exp());
+            assert_eq!(reflected_y.eval(&x), -x.exp());
+        }
     }
 
+    #[cfg(feature = "bigdecimal")]
     #[test]
-    fn eval_cos() {
+    fn eval_bigdecimal_exact() {
 
-        let cos = UnaryFunction::Cos.new();
-        assert_eq!(cos.eval(&5.0), 5f64.cos());
+        let x = UnaryFunction::Id.new();
+        let f = Rc::clone(&x).mul(x).add(UnaryFunction::Const(1.0).new());
+        let input: BigDecimal = "1.000000000000000000000000001".parse().unwrap();
+        let expected: BigDecimal = "2.000000000000000000000000002000000000000000000000000001".parse().unwrap();
+
+        assert_eq!(f.eval_bigdecimal(&input, 10), Some(expected));
+        assert_eq!(UnaryFunction::Sin.new().eval_bigdecimal(&input, 10), None);
+
+        let third = UnaryFunction::Const(1.0).new().div(UnaryFunction::Const(3.0).new());
+        assert_eq!(third.eval_bigdecimal(&input, 40), Some("0.3333333333333333333333333333333333333333".parse().unwrap()));
     }
 
     #[test]
-    fn eval_exp() {
+    fn diff_symmetric_quotient_shares_denominator() {
 
-        let exp = UnaryFunction::Exp.new();
-        assert_eq!(exp.eval(&6.0), 6f64.exp());
+        let x = UnaryFunction::Id.new();
+        let bottom = SumFunction::from_many(&[UnaryFunction::Exp.new(), UnaryFunction::Cos.new(), Rc::clone(&x).mul(Rc::clone(&x)), UnaryFunction::Const(3.0).new()]);
+        let quotient = UnaryFunction::Sin.new().div(bottom);
+
+        let (plain, shared) = (quotient.diff(), quotient.diff_symmetric_quotient());
+        assert!(shared.count_nodes() < plain.count_nodes());
+
+        for x in [-1.0, 0.5, 2.0] {
+
+            assert!((shared.eval(&x) - plain.eval(&x)).abs() < 1e-12);
+        }
     }
 
     #[test]
-    fn eval_log() {
+    fn eval_saturating_exp_exp() {
+
+        let exp_exp = UnaryFunction::Exp.new().of(UnaryFunction::Exp.new());
+        assert_eq!(exp_exp.eval(&10.0), f64::INFINITY);
+        assert_eq!(exp_exp.eval_saturating(&10.0), f64::MAX);
+
+        let falling = UnaryFunction::Exp.new().of(UnaryFunction::Exp.new()).neg().mul(UnaryFunction::Const(2.0).new());
+        assert_eq!(falling.eval_saturating(&10.0), f64::MIN);
+        assert_eq!(falling.eval_saturating(&0.0), falling.eval(&0.0));
+    }
+
+    #[test]
+    fn simplify_double_negation_in_difference_zero() {
+
+        let original = UnaryFunction::Id.new().sub(UnaryFunction::Const(0.0).new().sub(UnaryFunction::Sin.new()));
+        let simplified = original.simplify_double_negation_in_difference();
+        assert_eq!(format!("{}", simplified), "($) + (sin($))");
+
+        for x in [-2.0, 0.3, 1.7] {
+
+            assert_eq!(simplified.eval(&x), original.eval(&x));
+        }
+
+        let nested = UnaryFunction::Exp.new().sub(UnaryFunction::Cos.new().sub(UnaryFunction::Sin.new()));
+        assert_eq!(format!("{}", nested.simplify_double_negation_in_difference()), "((exp($)) - (cos($))) + (sin($))");
+    }
+
+    #[test]
+    fn linear_combination_terms() {
+
+        let x = UnaryFunction::Id.new();
+        let sum = UnaryFunction::Const(2.0).new().mul(UnaryFunction::Sin.new())
+            .add(UnaryFunction::Const(3.0).new().mul(Rc::clone(&x)))
+            .sub(UnaryFunction::Exp.new())
+            .add(UnaryFunction::Const(4.0).new());
+
+        let described: Vec<(f64, String)> = sum.linear_combination().into_iter().map(|(c, base)| (c, base.to_string())).collect();
+        let expected = vec![(2.0, "sin($)"), (3.0, "$"), (-1.0, "exp($)"), (4.0, "1")];
+        assert_eq!(described, expected.into_iter().map(|(c, base)| (c, String::from(base))).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sample_grid_sum_corners() {
+
+        let sum = UnaryFunction::Id.new().add(VariableFunction::new("y"));
+        let surface = sum.sample_grid((0.0, 1.0), (10.0, 20.0), 3, 2);
+
+        assert_eq!(surface, vec![vec![10.0, 10.5, 11.0], vec![20.0, 20.5, 21.0]]);
+    }
+
+    #[test]
+    fn wronskian_independence() {
+
+        let (sin, cos) = (UnaryFunction::Sin.new(), UnaryFunction::Cos.new());
+        let x = UnaryFunction::Id.new();
+        let doubled = UnaryFunction::Const(2.0).new().mul(Rc::clone(&x));
+
+        for t in [-2.0, 0.0, 0.7, 3.0] {
+
+            assert!((wronskian(&sin, &cos).eval(&t) + 1.0).abs() < 1e-12);
+            assert_eq!(wronskian(&x, &doubled).eval(&t), 0.0);
+        }
+    }
+
+    #[test]
+    fn ode_residual_harmonic() {
+
+        let coeffs = [UnaryFunction::Const(1.0).new(), UnaryFunction::Const(0.0).new(), UnaryFunction::Const(1.0).new()];
+        let residual = UnaryFunction::Sin.new().ode_residual(&coeffs);
+
+        for t in [-2.0, 0.0, 0.7, 3.0] {
+
+            assert!(residual.eval(&t).abs() < 1e-12);
+        }
+
+        assert!(UnaryFunction::Exp.new().ode_residual(&coeffs).eval(&1.0).abs() > 1.0);
+    }
+
+    #[test]
+    fn expand_compound_denominator() {
+
+        let x = UnaryFunction::Id.new();
+        let top = UnaryFunction::Sin.new().add(Rc::clone(&x).mul(Rc::clone(&x)));
+        let bottom = UnaryFunction::Exp.new().add(UnaryFunction::Const(2.0).new());
+        let quotient = Rc::clone(&top).div(Rc::clone(&bottom));
+        let nested = top.div(bottom.div(UnaryFunction::Cos.new()));
+
+        for function in [&quotient, &nested] {
+
+            let expanded = function.expand();
+
+            for t in [-1.5, 0.2, 2.0] {
+
+                assert!((expanded.eval(&t) - function.eval(&t)).abs() < 1e-12);
+            }
+        }
+
+        assert_eq!(nested.expand_vec().len(), 2);
+        assert!(nested.expand_vec().iter().all(|term| match Shape::of(&**term) {
+
+            Shape::Quotient(_, bottom) => !matches!(Shape::of(&*bottom), Shape::Quotient(_, _)),
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn eval_policy_log_negative() {
 
         let log = UnaryFunction::Log.new();
-        assert_eq!(log.eval(&7.0), 7f64.ln());
+        let shifted = Rc::clone(&log).of(UnaryFunction::Id.new()).add(UnaryFunction::Const(1.0).new());
+
+        assert!(log.eval_policy(&-1.0, NanPolicy::Propagate).unwrap().is_nan());
+        assert_eq!(log.eval_policy(&-1.0, NanPolicy::ZeroFill), Ok(0.0));
+        assert_eq!(shifted.eval_policy(&-1.0, NanPolicy::ZeroFill), Ok(1.0));
+        assert!(matches!(shifted.eval_policy(&-1.0, NanPolicy::Error), Err(EvalError::NonFinite(ref node, value)) if node == "ln($)" && value.is_nan()));
+        assert_eq!(shifted.eval_policy(&1.0, NanPolicy::Error), Ok(1.0));
     }
 
     #[test]
-    fn eval_sum() {
+    fn as_single_fraction_reciprocals() {
 
-        let three = UnaryFunction::Const(3.0).new().add(UnaryFunction::Const(2.0).new());
-        assert_eq!(three.eval(&8.0), 5.0);
+        let x = UnaryFunction::Id.new();
+        let x_plus_one = Rc::clone(&x).add(UnaryFunction::Const(1.0).new());
+        let one = || UnaryFunction::Const(1.0).new();
+        let sum = one().div(Rc::clone(&x)).add(one().div(Rc::clone(&x_plus_one)));
+        let fraction = sum.as_single_fraction();
+
+        match Shape::of(&*fraction) {
+
+            Shape::Quotient(top, bottom) => {
+
+                assert_eq!(top.as_polynomial(), Some(vec![1.0, 2.0]));
+                assert!(bottom.structurally_equals(&*Rc::clone(&x).mul(x_plus_one)));
+            },
+
+            _ => panic!("Expected a quotient, got {}", fraction),
+        }
+
+        for t in [-3.0, 0.5, 2.0] {
+
+            assert!((fraction.eval(&t) - sum.eval(&t)).abs() < 1e-12);
+        }
+
+        let nested = IntPowFunction::new(UnaryFunction::Sin.new().div(Rc::clone(&x)), -2).sub(UnaryFunction::Exp.new().div(UnaryFunction::Cos.new()).neg());
+        let nested_fraction = nested.as_single_fraction();
+
+        for t in [-3.0, 0.5, 2.0] {
+
+            assert!((nested_fraction.eval(&t) - nested.eval(&t)).abs() < 1e-9 * nested.eval(&t).abs());
+        }
     }
 
     #[test]
-    fn eval_diff() {
+    fn numerator_denominator_sinc() {
 
-        let three_minus_x = UnaryFunction::Const(3.0).new().sub(UnaryFunction::Id.new());
-        assert_eq!(three_minus_x.eval(&9.0), -6.0);
+        let sinc = UnaryFunction::Sin.new().div(UnaryFunction::Id.new());
+        assert!(sinc.numerator().structurally_equals(&*UnaryFunction::Sin.new()));
+        assert!(sinc.denominator().structurally_equals(&*UnaryFunction::Id.new()));
+
+        let exp = UnaryFunction::Exp.new();
+        assert!(exp.numerator().structurally_equals(&*exp));
+        assert!(exp.denominator().structurally_equals(&*UnaryFunction::Const(1.0).new()));
     }
 
     #[test]
-    fn eval_neg() {
+    fn poles_hyperbola_and_sinc() {
 
-        let minus_x = UnaryFunction::Id.new().neg();
-        assert_eq!(minus_x.eval(&10.0), -10.0);
+        let x = UnaryFunction::Id.new();
+        let hyperbola = UnaryFunction::Const(1.0).new().div(Rc::clone(&x).mul(Rc::clone(&x)).sub(UnaryFunction::Const(1.0).new()));
+        let poles = hyperbola.poles(-2.0, 2.0, 10, 1e-9);
+        assert_eq!(poles.len(), 2);
+        assert!((poles[0] + 1.0).abs() < 1e-6 && (poles[1] - 1.0).abs() < 1e-6);
+
+        let sinc = UnaryFunction::Sin.new().div(Rc::clone(&x));
+        assert!(sinc.poles(-1.0, 1.0, 10, 1e-9).is_empty());
+
+        let tiny = UnaryFunction::Const(1e-12).new().div(Rc::clone(&x));
+        assert_eq!(tiny.poles(-1.0, 1.0, 10, 1e-9).len(), 1);
+
+        let squared_sinc = UnaryFunction::Sin.new().mul(UnaryFunction::Sin.new()).div(x);
+        assert!(squared_sinc.poles(-1.0, 1.0, 10, 1e-9).is_empty());
     }
 
     #[test]
-    fn eval_mul() {
+    fn residue_simple_poles() {
 
-        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
-        assert_eq!(x_sqr.eval(&11.0), 121.0);
+        let x = UnaryFunction::Id.new();
+        let shifted = UnaryFunction::Const(1.0).new().div(Rc::clone(&x).sub(UnaryFunction::Const(2.0).new()));
+        assert_eq!(shifted.residue_at(2.0), 1.0);
+
+        // 1 / (x^2 - 1) = (1/2) / (x - 1) - (1/2) / (x + 1)
+        let hyperbola = UnaryFunction::Const(1.0).new().div(Rc::clone(&x).mul(x).sub(UnaryFunction::Const(1.0).new()));
+        assert_eq!(hyperbola.residue_at(1.0), 0.5);
+        assert_eq!(hyperbola.residue_at(-1.0), -0.5);
     }
 
     #[test]
-    fn eval_div() {
+    fn visitor_defaults_walk_children() {
 
-        let inv = UnaryFunction::Const(1.0).new().div(UnaryFunction::Id.new());
-        assert_eq!(inv.eval(&12.0), 1.0 / 12.0);
+        struct Unaries(Vec<UnaryFunction>);
+
+        impl Visitor for Unaries {
+
+            fn visit_unary(&mut self, unary: &UnaryFunction) { self.0.push(*unary); }
+        }
+
+        let f = IntPowFunction::new(UnaryFunction::Sin.new(), 2).div(ClampFunction::new(UnaryFunction::Exp.new(), 0.0, 1.0).neg());
+
+        let mut visitor = Unaries(Vec::new());
+        f.accept(&mut visitor);
+
+        assert_eq!(visitor.0, vec![UnaryFunction::Sin, UnaryFunction::Exp]);
     }
 
     #[test]
-    fn eval_comp() {
+    fn polynomial_shape_is_stable() {
 
-        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
-        let sin_of_sqr = UnaryFunction::Sin.new().of(x_sqr);
-        assert_eq!(sin_of_sqr.eval(&-1.0), 1f64.sin());
+        let polynomial = PolynomialFunction::new(vec![1.0, -2.0, 3.0]);
+
+        assert!(matches!(Shape::of(&*polynomial.simplify()), Shape::Polynomial(ref coeffs) if coeffs == &[1.0, -2.0, 3.0]));
+        assert!(matches!(Shape::of(&*polynomial.to_function()), Shape::Polynomial(_)));
+        assert_eq!(polynomial.memory_footprint(), std::mem::size_of::<PolynomialFunction>() + 2 * std::mem::size_of::<usize>());
+
+        let sum = Rc::clone(&polynomial).add(UnaryFunction::Sin.new());
+        let replaced = sum.replace_ptr(&polynomial, &UnaryFunction::Cos.new());
+        assert!(replaced.structurally_equals(&*UnaryFunction::Cos.new().add(UnaryFunction::Sin.new())));
+
+        assert_eq!(polynomial.count_nodes(), polynomial_tree(&[1.0, -2.0, 3.0]).count_nodes());
+        assert_eq!(polynomial.diff().eval(&2.0), 10.0);
+        assert!(polynomial.is_polynomial_in("x"));
     }
 
     #[test]
-    fn eval_expand() {
+    fn memo_survives_rebuilding() {
 
-        let x_sqr = UnaryFunction::Id.new().mul(UnaryFunction::Id.new());
-        let x_plus_sqr = UnaryFunction::Id.new().add(x_sqr);
-        assert_eq!(x_plus_sqr.expand().eval(&-2.0), x_plus_sqr.eval(&-2.0));
+        let memo = Rc::new(MemoFunction::wrap(UnaryFunction::Gamma.new().mul(UnaryFunction::Erf.new())));
+        let function: Function = Rc::clone(&memo) as Function;
+
+        let copy = function.to_function();
+        copy.eval(&1.7);
+        assert_eq!(memo.cache_len(), 1);
+
+        function.compile().eval(2.5);
+        assert_eq!(memo.cache_len(), 2);
+
+        let (value, steps) = function.eval_trace(3.5);
+        assert_eq!(value, memo.eval(&3.5));
+        assert_eq!(steps.len(), 1);
+        assert_eq!(memo.cache_len(), 3);
+
+        function.reflect_x().eval(&-4.5);
+        assert_eq!(memo.cache_len(), 4);
+
+        assert!(matches!(Shape::of(&*function.simplify()), Shape::Memo(_)));
+        assert!(copy.structurally_equals(&*memo.inner));
+
+        let id = UnaryFunction::Id.new();
+        let polynomial = MemoFunction::new(Rc::clone(&id).mul(id).add(UnaryFunction::Const(1.0).new()));
+        assert!(polynomial.is_polynomial_in("x"));
     }
 
     /*
@@ -3,7 +3,9 @@ use diff_func::*;
 
 fn diff_and_print(name: &str, f: Function) {
 
-    println!("{name}(x) = {}, {name}'(x) = {}", f.to_string().replace("$", "x"), f.diff().to_string().replace("$", "x"), name = name);
+    let derivative = f.diff_simplified();
+
+    println!("{} = {}, {} = {}", f.wrap_with_name(name), f.to_string_in("x"), derivative.wrap_with_name(&format!("{}'", name)), derivative.to_string_in("x"));
 }
 
 fn main() {